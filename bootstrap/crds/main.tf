@@ -72,16 +72,68 @@ resource "kubernetes_manifest" "customresourcedefinition_baliusworkers_demeter_r
                     "authToken" = {
                       "type" = "string"
                     }
+                    "captureRequests" = {
+                      "description" = "Record the worker's requests and responses so they can be replayed. Disabled by default."
+                      "nullable"    = true
+                      "type"        = "boolean"
+                    }
                     "config" = {
                       "additionalProperties" = true
                       "type"                 = "object"
                     }
+                    "deletionPolicy" = {
+                      "description" = "What happens to the worker's signing keys and data once it is deleted. Defaults to `retain`."
+                      "enum" = [
+                        "retain",
+                        "delete",
+                      ]
+                      "nullable" = true
+                      "type"     = "string"
+                    }
                     "displayName" = {
                       "type" = "string"
                     }
+                    "keyVersions" = {
+                      "additionalProperties" = {
+                        "format"  = "uint64"
+                        "minimum" = 0
+                        "type"    = "integer"
+                      }
+                      "description" = "Version to sign with for each of the worker's keys. Keys not listed sign with their latest version."
+                      "nullable"    = true
+                      "type"        = "object"
+                    }
                     "network" = {
                       "type" = "string"
                     }
+                    "openrpcUrl" = {
+                      "description" = "URL of the worker's OpenRPC document, answered to `rpc.discover`. `s3://` and `http(s)://` URLs are supported. When not set, `rpc.discover` is handled by the worker."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "signingPolicy" = {
+                      "description" = "Limits on what and how often the worker can sign. Unrestricted when not set."
+                      "nullable"    = true
+                      "properties" = {
+                        "allowedPayloads" = {
+                          "description" = "Payloads the worker is allowed to sign. Defaults to `any`."
+                          "enum" = [
+                            "any",
+                            "txHash",
+                          ]
+                          "nullable" = true
+                          "type"     = "string"
+                        }
+                        "maxSignaturesPerMinute" = {
+                          "description" = "Maximum number of signatures per minute. Unlimited when not set."
+                          "format"      = "uint32"
+                          "minimum"     = 0
+                          "nullable"    = true
+                          "type"        = "integer"
+                        }
+                      }
+                      "type" = "object"
+                    }
                     "throughputTier" = {
                       "type" = "string"
                     }
@@ -120,6 +172,38 @@ resource "kubernetes_manifest" "customresourcedefinition_baliusworkers_demeter_r
                       "nullable" = true
                       "type"     = "string"
                     }
+                    "keys" = {
+                      "description" = "Signing keys created by the worker, kept up to date by the instance running it."
+                      "items" = {
+                        "properties" = {
+                          "algorithm" = {
+                            "type" = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                          "publicKey" = {
+                            "description" = "Hex encoded public key of the latest version."
+                            "type"        = "string"
+                          }
+                          "version" = {
+                            "description" = "Latest version of the key."
+                            "format"      = "uint64"
+                            "minimum"     = 0
+                            "type"        = "integer"
+                          }
+                        }
+                        "required" = [
+                          "algorithm",
+                          "name",
+                          "publicKey",
+                          "version",
+                        ]
+                        "type" = "object"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
                   }
                   "required" = [
                     "authToken",
@@ -8,14 +8,16 @@ resource "kubernetes_config_map" "cfg" {
     "baliusd.toml" = "${templatefile(
       "${path.module}/baliusd.toml.tftpl",
       {
-        utxorpc_url                = var.utxorpc_url,
-        container_port             = local.container_port
-        prometheus_port            = local.prometheus_port
-        network                    = var.network
-        vault_address              = var.vault_address
-        vault_token                = var.vault_token
-        vault_token_renew_seconds  = var.vault_token_renew_seconds
-        vault_token_renew_interval = var.vault_token_renew_interval
+        utxorpc_url                 = var.utxorpc_url,
+        container_port              = local.container_port
+        prometheus_port             = local.prometheus_port
+        network                     = var.network
+        vault_address               = var.vault_address
+        vault_token                 = var.vault_token
+        vault_kubernetes_role       = var.vault_kubernetes_role
+        vault_token_renew_seconds   = var.vault_token_renew_seconds
+        vault_token_renew_increment = var.vault_token_renew_increment
+        proxy_secret                = var.proxy_secret
       }
     )}"
   }
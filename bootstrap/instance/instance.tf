@@ -161,6 +161,24 @@ resource "kubernetes_deployment_v1" "balius" {
             container_port = local.prometheus_port
             name           = "metrics"
           }
+
+          liveness_probe {
+            http_get {
+              path = "/healthz"
+              port = "metrics"
+            }
+            initial_delay_seconds = 10
+            period_seconds        = 10
+          }
+
+          readiness_probe {
+            http_get {
+              path = "/readyz"
+              port = "metrics"
+            }
+            period_seconds    = 10
+            failure_threshold = 3
+          }
         }
 
         volume {
@@ -19,19 +19,34 @@ variable "vault_address" {
 }
 
 variable "vault_token" {
-  type = string
+  type    = string
+  default = null
 }
 
+// When set, the instance logs in to vault using its service account token with this role instead
+// of using `vault_token`.
+variable "vault_kubernetes_role" {
+  type    = string
+  default = null
+}
+
+// Longest time between token renewals. Tokens are renewed earlier when their lease requires it.
 variable "vault_token_renew_seconds" {
   type    = number
   default = 600
 }
 
-variable "vault_token_renew_interval" {
+// Lease extension requested on each renewal.
+variable "vault_token_renew_increment" {
   type    = string
   default = "24h"
 }
 
+variable "proxy_secret" {
+  type      = string
+  sensitive = true
+}
+
 variable "utxorpc_url" {
   type = string
 }
@@ -30,6 +30,7 @@ module "proxy" {
   resources       = var.proxy_resources
   dns_names       = var.dns_names
   tolerations     = var.proxy_tolerations
+  proxy_secret    = var.proxy_secret
 }
 
 module "postgres" {
@@ -56,7 +57,9 @@ module "instances" {
   network                 = each.value.network
   utxorpc_url             = each.value.utxorpc_url
   vault_token             = each.value.vault_token
+  vault_kubernetes_role   = each.value.vault_kubernetes_role
   vault_address           = each.value.vault_address
+  proxy_secret            = var.proxy_secret
   replicas                = coalesce(each.value.replicas, 1)
   credentials_secret_name = "demeter-workers-credentials"
   postgres_name           = local.postgres_name
@@ -77,6 +77,11 @@ resource "kubernetes_deployment_v1" "balius_proxy" {
             value = "${var.namespace}.svc.cluster.local"
           }
 
+          env {
+            name  = "BALIUS_PROXY_SECRET"
+            value = var.proxy_secret
+          }
+
           env {
             name  = "DEFAULT_BALIUS_VERSION"
             value = "v2"
@@ -75,6 +75,11 @@ variable "balius_port" {
   default = 3000
 }
 
+variable "proxy_secret" {
+  type      = string
+  sensitive = true
+}
+
 variable "dns_names" {
   type = list(string)
 }
@@ -133,6 +133,13 @@ variable "proxy_replicas" {
   default = 1
 }
 
+// Shared secret the proxy authenticates itself to instances with. Instances refuse to start
+// without it.
+variable "proxy_secret" {
+  type      = string
+  sensitive = true
+}
+
 variable "proxy_resources" {
   type = object({
     limits = object({
@@ -184,13 +191,14 @@ variable "proxy_tolerations" {
 
 variable "instances" {
   type = map(object({
-    image         = string
-    salt          = string
-    network       = string
-    utxorpc_url   = string
-    vault_address = string
-    vault_token   = string
-    replicas      = optional(number)
+    image                 = string
+    salt                  = string
+    network               = string
+    utxorpc_url           = string
+    vault_address         = string
+    vault_token           = optional(string)
+    vault_kubernetes_role = optional(string)
+    replicas              = optional(number)
     resources = optional(object({
       limits = object({
         cpu    = string
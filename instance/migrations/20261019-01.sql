@@ -0,0 +1,8 @@
+ALTER TABLE logs
+    ADD COLUMN context_json JSONB,
+    ADD COLUMN slot BIGINT,
+    ADD COLUMN request_id TEXT;
+
+CREATE INDEX logs_worker_slot_idx ON logs (worker, slot);
+CREATE INDEX logs_worker_request_id_idx ON logs (worker, request_id);
+CREATE INDEX logs_context_json_idx ON logs USING GIN (context_json jsonb_path_ops);
@@ -0,0 +1,8 @@
+CREATE TABLE keystore (
+    name VARCHAR(600) NOT NULL,
+    version BIGINT NOT NULL,
+    salt BYTEA NOT NULL,
+    nonce BYTEA NOT NULL,
+    ciphertext BYTEA NOT NULL,
+    PRIMARY KEY (name, version)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE signatures (
+    id BIGSERIAL PRIMARY KEY,
+    timestamp TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    worker VARCHAR(320) NOT NULL,
+    key_name VARCHAR(255) NOT NULL,
+    key_version BIGINT,
+    payload_hash CHAR(64) NOT NULL,
+    outcome VARCHAR(20) NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX idx_signatures_worker_timestamp ON signatures(worker, timestamp DESC);
@@ -0,0 +1,20 @@
+CREATE TABLE deleted_workers (
+    worker VARCHAR(320) NOT NULL,
+    uid VARCHAR(64) NOT NULL,
+    shard TEXT NOT NULL,
+    policy VARCHAR(10) NOT NULL,
+    deleted_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    purged_at TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (worker, uid)
+);
+
+CREATE TABLE signer_keys (
+    worker VARCHAR(400) NOT NULL,
+    key_name VARCHAR(255) NOT NULL,
+    algorithm VARCHAR(50) NOT NULL,
+    backend_key VARCHAR(600) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (worker, key_name)
+);
+
+CREATE INDEX idx_signer_keys_backend_key ON signer_keys(backend_key);
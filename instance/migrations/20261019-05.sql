@@ -0,0 +1,12 @@
+-- Worker ids are {namespace}.{name}.
+ALTER TABLE kv ALTER COLUMN worker TYPE VARCHAR(320);
+ALTER TABLE logs ALTER COLUMN worker TYPE VARCHAR(320);
+ALTER TABLE cursors ALTER COLUMN worker TYPE VARCHAR(320);
+
+-- Bare worker names whose rows were handed over to a namespace-qualified id through the admin
+-- API. Each name is handed over at most once.
+CREATE TABLE legacy_workers (
+    legacy VARCHAR(255) PRIMARY KEY,
+    worker VARCHAR(320) NOT NULL UNIQUE,
+    migrated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,16 @@
+CREATE TABLE captured_requests (
+    id BIGSERIAL PRIMARY KEY,
+    timestamp TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    worker VARCHAR(320) NOT NULL,
+    request_id TEXT,
+    method TEXT NOT NULL,
+    params BYTEA NOT NULL,
+    response_kind VARCHAR(10),
+    response BYTEA,
+    error TEXT,
+    duration_ms BIGINT NOT NULL,
+    slot BIGINT,
+    block_hash VARCHAR(64)
+);
+
+CREATE INDEX idx_captured_requests_worker ON captured_requests(worker, timestamp);
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use aws_lc_rs::constant_time;
+use balius_runtime::wit::balius::app::sign as wit;
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use operator::kube::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tokio_postgres::NoTls;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument};
+use warp::{
+    http::StatusCode,
+    hyper::body::Bytes,
+    reply::{Reply, Response},
+    Filter as _,
+};
+
+use crate::{
+    capture::{self, Replayer},
+    cleanup,
+    config::Config,
+    migration::{self, MigrationError},
+    runtime::{self, Workers},
+    signer::{self, SignatureQuery, Signer},
+    store,
+};
+
+const DEFAULT_SIGNATURES_LIMIT: i64 = 100;
+const MAX_SIGNATURES_LIMIT: i64 = 1000;
+const DEFAULT_CAPTURES_LIMIT: i64 = 100;
+const MAX_CAPTURES_LIMIT: i64 = 1000;
+
+/// State shared by the admin endpoints.
+#[derive(Clone)]
+pub struct AdminState {
+    pub signer: Arc<Mutex<Signer>>,
+    pub pool: Pool<PostgresConnectionManager<NoTls>>,
+    pub client: Client,
+    pub workers: Workers,
+    pub shard: String,
+    pub replayer: Replayer,
+}
+
+fn error_reply(status: StatusCode, error: impl ToString) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&json!({ "error": error.to_string() })),
+        status,
+    )
+    .into_response()
+}
+
+fn sign_error_reply(err: wit::SignError) -> Response {
+    match err {
+        wit::SignError::KeyNotFound(x) => error_reply(StatusCode::NOT_FOUND, x),
+        err => error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:?}")),
+    }
+}
+
+async fn rotate_key(state: AdminState, worker: String, key: String) -> Response {
+    let result = state.signer.lock().await.rotate_key(&worker, &key).await;
+    match result {
+        Ok((version, public_key)) => {
+            runtime::try_patch_keys(&state.client, &worker, &state.signer).await;
+            warp::reply::json(&json!({
+                "version": version,
+                "publicKey": hex::encode(public_key),
+            }))
+            .into_response()
+        }
+        Err(err) => sign_error_reply(err),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignaturesParams {
+    key_name: Option<String>,
+    outcome: Option<String>,
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+async fn list_signatures(state: AdminState, worker: String, params: SignaturesParams) -> Response {
+    let query = SignatureQuery {
+        key_name: params.key_name,
+        outcome: params.outcome,
+        before: params.before,
+        limit: params
+            .limit
+            .unwrap_or(DEFAULT_SIGNATURES_LIMIT)
+            .clamp(1, MAX_SIGNATURES_LIMIT),
+    };
+    match signer::list_signatures(&state.pool, &worker, &query).await {
+        Ok(records) => warp::reply::json(&json!({ "signatures": records })).into_response(),
+        Err(err) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CapturesParams {
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+async fn list_captures(state: AdminState, worker: String, params: CapturesParams) -> Response {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_CAPTURES_LIMIT)
+        .clamp(1, MAX_CAPTURES_LIMIT);
+    match capture::list_captures(&state.pool, &worker, params.before, limit).await {
+        Ok(captures) => {
+            let captures: Vec<_> = captures.iter().map(|x| x.to_json()).collect();
+            warp::reply::json(&json!({ "captures": captures })).into_response()
+        }
+        Err(err) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+/// Worker version a capture is replayed against. The loaded one is used when not set.
+#[derive(Debug, Default, Deserialize)]
+struct ReplayRequest {
+    url: Option<String>,
+    /// Config of the worker, the loaded worker's one by default.
+    config: Option<Value>,
+}
+
+async fn replay_capture(state: AdminState, id: i64, body: Bytes) -> Response {
+    let request: ReplayRequest = if body.is_empty() {
+        ReplayRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(x) => x,
+            Err(err) => return error_reply(StatusCode::BAD_REQUEST, err),
+        }
+    };
+
+    let capture = match capture::get_capture(&state.pool, id).await {
+        Ok(Some(capture)) => capture,
+        Ok(None) => return error_reply(StatusCode::NOT_FOUND, "capture not found"),
+        Err(err) => return error_reply(StatusCode::INTERNAL_SERVER_ERROR, err),
+    };
+
+    let loaded = state.workers.loaded.get(&capture.worker).await;
+    let Some(url) = request
+        .url
+        .or_else(|| loaded.as_ref().map(|x| x.url.clone()))
+    else {
+        return error_reply(
+            StatusCode::NOT_FOUND,
+            "worker isn't loaded, a url is required",
+        );
+    };
+    let config = request
+        .config
+        .or_else(|| loaded.map(|x| x.config))
+        .unwrap_or_else(|| json!({}));
+
+    match state.replayer.replay(&capture, &url, config).await {
+        Ok(replay) => warp::reply::json(&json!({
+            "capture": capture.to_json(),
+            "replay": replay,
+        }))
+        .into_response(),
+        Err(err) => error_reply(StatusCode::UNPROCESSABLE_ENTITY, format!("{err:?}")),
+    }
+}
+
+/// Purge a deleted worker's keys and data now, whatever its deletion policy, so that its id can
+/// be used again.
+async fn release_worker(state: AdminState, worker: String) -> Response {
+    match cleanup::release_worker(&state.pool, &state.signer, &worker).await {
+        Ok(true) => warp::reply::json(&json!({ "worker": worker })).into_response(),
+        Ok(false) => error_reply(StatusCode::NOT_FOUND, "no deleted worker to release"),
+        Err(err) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+/// Hand the rows kept under the worker's bare name, from before ids were namespace-qualified,
+/// over to the worker.
+async fn migrate_legacy_worker(state: AdminState, worker: String) -> Response {
+    match migration::migrate_legacy_worker(&state.pool, &worker).await {
+        Ok(moved) => {
+            warp::reply::json(&json!({ "worker": worker, "moved": moved })).into_response()
+        }
+        Err(err @ MigrationError::InvalidWorker(_)) => error_reply(StatusCode::BAD_REQUEST, err),
+        Err(err @ (MigrationError::AlreadyMigrated(..) | MigrationError::Conflict(..))) => {
+            error_reply(StatusCode::CONFLICT, err)
+        }
+        Err(err @ MigrationError::Internal(_)) => {
+            error_reply(StatusCode::INTERNAL_SERVER_ERROR, err)
+        }
+    }
+}
+
+/// List the workers loaded in the runtime and the ones that failed to load.
+async fn list_workers(state: AdminState) -> Response {
+    let cursors = match store::worker_cursors(&state.pool, &state.shard).await {
+        Ok(cursors) => cursors,
+        Err(err) => return error_reply(StatusCode::INTERNAL_SERVER_ERROR, err),
+    };
+
+    let mut loaded: Vec<_> = state
+        .workers
+        .loaded
+        .list()
+        .await
+        .into_iter()
+        .map(|(worker, loaded)| {
+            let (cursor, slot) = cursors.get(&worker).copied().unzip();
+            json!({
+                "worker": worker,
+                "url": loaded.url,
+                "configHash": loaded.config_hash,
+                "loadedAt": loaded.loaded_at,
+                "cursor": cursor,
+                "slot": slot.flatten(),
+            })
+        })
+        .collect();
+    loaded.sort_by(|a, b| a["worker"].as_str().cmp(&b["worker"].as_str()));
+
+    let mut failed: Vec<_> = state.workers.failed.list().await.into_iter().collect();
+    failed.sort();
+    let failed: Vec<_> = failed
+        .into_iter()
+        .map(|(worker, reason)| json!({ "worker": worker, "reason": reason }))
+        .collect();
+
+    warp::reply::json(&json!({ "loaded": loaded, "failed": failed })).into_response()
+}
+
+/// Serve the admin API on `admin_addr`. Every request must carry the configured admin token as
+/// a bearer token.
+#[instrument("admin", skip_all)]
+pub async fn serve(
+    config: &Config,
+    state: AdminState,
+    cancel: CancellationToken,
+) -> miette::Result<()> {
+    let Some(address) = config.admin_addr else {
+        info!("admin_addr not set, admin API disabled");
+        return Ok(());
+    };
+    let Some(token) = config.admin_token.clone() else {
+        miette::bail!("admin_token must be set to serve the admin API");
+    };
+
+    let expected = Arc::new(format!("Bearer {token}"));
+    let authorized = warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            async move {
+                match header {
+                    Some(header)
+                        if constant_time::verify_slices_are_equal(
+                            header.as_bytes(),
+                            expected.as_bytes(),
+                        )
+                        .is_ok() =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one();
+
+    let with_state = warp::any().map(move || state.clone());
+
+    let workers = warp::path!("workers")
+        .and(warp::get())
+        .and(with_state.clone())
+        .then(list_workers);
+
+    let rotate = warp::path!("workers" / String / "keys" / String / "rotate")
+        .and(warp::post())
+        .and(with_state.clone())
+        .then(|worker, key, state| rotate_key(state, worker, key));
+
+    let signatures = warp::path!("workers" / String / "signatures")
+        .and(warp::get())
+        .and(warp::query::<SignaturesParams>())
+        .and(with_state.clone())
+        .then(|worker, params, state| list_signatures(state, worker, params));
+
+    let captures = warp::path!("workers" / String / "captures")
+        .and(warp::get())
+        .and(warp::query::<CapturesParams>())
+        .and(with_state.clone())
+        .then(|worker, params, state| list_captures(state, worker, params));
+
+    let replay = warp::path!("captures" / i64 / "replay")
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .then(|id, body, state| replay_capture(state, id, body));
+
+    let release = warp::path!("deleted-workers" / String / "release")
+        .and(warp::post())
+        .and(with_state.clone())
+        .then(|worker, state| release_worker(state, worker));
+
+    let migrate = warp::path!("workers" / String / "migrate-legacy")
+        .and(warp::post())
+        .and(with_state.clone())
+        .then(|worker, state| migrate_legacy_worker(state, worker));
+
+    let routes = authorized
+        .and(
+            workers
+                .or(rotate)
+                .or(signatures)
+                .or(captures)
+                .or(replay)
+                .or(release)
+                .or(migrate),
+        )
+        .recover(handle_rejection)
+        .with(warp::log("admin"));
+
+    let (addr, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(address, cancel.cancelled_owned());
+
+    info!(%addr, "Admin server listening");
+
+    server.await;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+async fn handle_rejection(rejection: warp::Rejection) -> Result<Response, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(error_reply(StatusCode::UNAUTHORIZED, "invalid admin token"))
+    } else if rejection.is_not_found() {
+        Ok(error_reply(StatusCode::NOT_FOUND, "not found"))
+    } else {
+        Err(rejection)
+    }
+}
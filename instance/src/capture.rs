@@ -0,0 +1,714 @@
+/// Capture and replay of worker requests.
+///
+///
+/// Workers with `captureRequests` set in their spec get every request they handle recorded, along
+/// with its response or error, how long it took and the block the worker's cursor pointed to at
+/// the time. Captures are kept for `capture_retention_hours` and can be replayed from the admin
+/// API against the loaded version of the worker or against a different one. Replays never use
+/// the worker's signing keys, see [`ReplaySigner`].
+///
+/// ```sql
+/// CREATE TABLE captured_requests (
+///     id BIGSERIAL PRIMARY KEY,
+///     timestamp TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+///     worker VARCHAR(320) NOT NULL,
+///     request_id TEXT,
+///     method TEXT NOT NULL,
+///     params BYTEA NOT NULL,          -- JSON, or CBOR for CBOR requests
+///     response_kind VARCHAR(10),      -- json, cbor, tx or ack, NULL when the request failed
+///     response BYTEA,
+///     error TEXT,
+///     duration_ms BIGINT NOT NULL,
+///     slot BIGINT,                    -- chain point of the worker's cursor
+///     block_hash VARCHAR(64)
+/// );
+///
+/// CREATE INDEX idx_captured_requests_worker ON captured_requests(worker, timestamp);
+/// ```
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use aws_lc_rs::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use balius_runtime::{
+    kv::{Kv, KvProvider},
+    ledgers,
+    sign::{Signer as RuntimeSigner, SignerProvider},
+    wit,
+    wit::balius::app::kv::{KvError, Payload},
+    wit::balius::app::sign as wit_sign,
+    Runtime, Store,
+};
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use miette::{Context, IntoDiagnostic};
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio_postgres::NoTls;
+use tokio_util::sync::CancellationToken;
+use tracing::{instrument, warn};
+
+use crate::{config::Config, kv::PostgresKv, runtime, server, store, store::PostgresStore};
+
+pub const DEFAULT_RETENTION_HOURS: u32 = 72;
+/// Shard the cursors of replayed workers are looked up in, so they never see the live ones.
+const REPLAY_SHARD: &str = "replay";
+
+/// Requests waiting to be written, past which new captures are dropped.
+const CHANNEL_CAPACITY: usize = 1024;
+/// Largest number of captures written in a single insert.
+const WRITE_BATCH_SIZE: usize = 100;
+
+/// A captured request waiting to be written.
+struct Capture {
+    worker: String,
+    request_id: Option<String>,
+    method: String,
+    params: Vec<u8>,
+    response_kind: Option<&'static str>,
+    response: Option<Vec<u8>>,
+    error: Option<String>,
+    duration_ms: i64,
+}
+
+/// Workers whose requests are captured. Captures are queued and written in batches by [`run`],
+/// so that capturing doesn't delay responses nor take more than one connection of the pool.
+#[derive(Clone)]
+pub struct RequestCapture {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    shard: String,
+    enabled: Arc<RwLock<HashSet<String>>>,
+    sender: mpsc::Sender<Capture>,
+    receiver: Arc<Mutex<Option<mpsc::Receiver<Capture>>>>,
+    dropped: Counter<u64>,
+}
+
+impl RequestCapture {
+    pub fn new(pool: &Pool<PostgresConnectionManager<NoTls>>, shard: &str) -> Self {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        Self {
+            pool: pool.clone(),
+            shard: shard.to_string(),
+            enabled: Default::default(),
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            dropped: global::meter("baliusd")
+                .u64_counter("worker_dropped_captures")
+                .with_description("Captured requests dropped because the write queue was full")
+                .build(),
+        }
+    }
+
+    pub async fn set(&self, worker_id: &str, enabled: bool) {
+        let mut workers = self.enabled.write().await;
+        if enabled {
+            workers.insert(worker_id.to_string());
+        } else {
+            workers.remove(worker_id);
+        }
+    }
+
+    pub async fn remove(&self, worker_id: &str) {
+        self.enabled.write().await.remove(worker_id);
+    }
+
+    pub async fn is_enabled(&self, worker_id: &str) -> bool {
+        self.enabled.read().await.contains(worker_id)
+    }
+
+    /// Queue a request to be stored. It is dropped when the queue is full.
+    pub fn record(
+        &self,
+        worker_id: &str,
+        request_id: Option<String>,
+        method: &str,
+        params: Vec<u8>,
+        result: Result<&wit::Response, String>,
+        duration: Duration,
+    ) {
+        let (response_kind, response, error) = match result {
+            Ok(response) => {
+                let (kind, bytes) = encode_response(response);
+                (Some(kind), bytes, None)
+            }
+            Err(err) => (None, None, Some(err)),
+        };
+        let capture = Capture {
+            worker: worker_id.to_string(),
+            request_id,
+            method: method.to_string(),
+            params,
+            response_kind,
+            response,
+            error,
+            duration_ms: duration.as_millis() as i64,
+        };
+
+        if self.sender.try_send(capture).is_err() {
+            self.dropped
+                .add(1, &[KeyValue::new("worker", worker_id.to_string())]);
+        }
+    }
+
+    /// Write a batch of captures along with the chain point of their workers.
+    async fn write(&self, batch: &mut Vec<Capture>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        // A request is still worth keeping without its chain point.
+        let mut chain_points = HashMap::new();
+        for capture in batch.iter() {
+            if chain_points.contains_key(&capture.worker) {
+                continue;
+            }
+            let chain_point = store::worker_chain_point(&self.pool, &self.shard, &capture.worker)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!(
+                        worker = capture.worker,
+                        err = err.to_string(),
+                        "failed to get chain point"
+                    );
+                    None
+                })
+                .map(|(slot, hash)| (slot as i64, hash));
+            chain_points.insert(capture.worker.clone(), chain_point);
+        }
+
+        let mut sql = String::from(
+            "INSERT INTO captured_requests
+             (worker, request_id, method, params, response_kind, response, error, duration_ms, slot, block_hash)
+             VALUES ",
+        );
+        let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> =
+            Vec::with_capacity(batch.len() * 10);
+        let slots: Vec<(Option<i64>, Option<String>)> = batch
+            .iter()
+            .map(|capture| chain_points[&capture.worker].clone().unzip())
+            .collect();
+        for (i, (capture, (slot, block_hash))) in batch.iter().zip(&slots).enumerate() {
+            if i > 0 {
+                sql.push(',');
+            }
+            let base = i * 10;
+            sql.push_str(&format!(
+                "(${}::TEXT, ${}::TEXT, ${}::TEXT, ${}::BYTEA, ${}::TEXT, ${}::BYTEA, ${}::TEXT, ${}::BIGINT, ${}::BIGINT, ${}::TEXT)",
+                base + 1,
+                base + 2,
+                base + 3,
+                base + 4,
+                base + 5,
+                base + 6,
+                base + 7,
+                base + 8,
+                base + 9,
+                base + 10
+            ));
+            params.push(&capture.worker);
+            params.push(&capture.request_id);
+            params.push(&capture.method);
+            params.push(&capture.params);
+            params.push(&capture.response_kind);
+            params.push(&capture.response);
+            params.push(&capture.error);
+            params.push(&capture.duration_ms);
+            params.push(slot);
+            params.push(block_hash);
+        }
+
+        let result = async {
+            let conn = self
+                .pool
+                .get()
+                .await
+                .into_diagnostic()
+                .context("getting connection")?;
+            conn.execute(&sql, &params)
+                .await
+                .into_diagnostic()
+                .context("inserting captured requests")
+        };
+        if let Err(err) = result.await {
+            warn!(
+                count = batch.len(),
+                err = err.to_string(),
+                "failed to capture requests"
+            );
+        }
+
+        batch.clear();
+    }
+}
+
+/// Write the queued captures in batches, and what is left of the queue on shutdown.
+#[instrument("capture", skip_all)]
+pub async fn run(capture: RequestCapture, cancel: CancellationToken) -> miette::Result<()> {
+    let Some(mut receiver) = capture.receiver.lock().await.take() else {
+        miette::bail!("capture writer is already running");
+    };
+
+    let mut batch = Vec::with_capacity(WRITE_BATCH_SIZE);
+    loop {
+        tokio::select! {
+            _ = receiver.recv_many(&mut batch, WRITE_BATCH_SIZE) => {
+                capture.write(&mut batch).await;
+            }
+            _ = cancel.cancelled() => {
+                while let Ok(pending) = receiver.try_recv() {
+                    batch.push(pending);
+                    if batch.len() >= WRITE_BATCH_SIZE {
+                        capture.write(&mut batch).await;
+                    }
+                }
+                capture.write(&mut batch).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Kind and bytes a response is stored with.
+fn encode_response(response: &wit::Response) -> (&'static str, Option<Vec<u8>>) {
+    match response {
+        wit::Response::Acknowledge => ("ack", None),
+        wit::Response::Json(x) => ("json", Some(x.clone())),
+        wit::Response::Cbor(x) => ("cbor", Some(x.clone())),
+        wit::Response::PartialTx(x) => ("tx", Some(x.clone())),
+    }
+}
+
+/// Bytes shown as JSON when they are, hex encoded otherwise.
+fn render(bytes: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(bytes) {
+        Ok(value) => json!({ "json": value }),
+        Err(_) => json!({ "hex": hex::encode(bytes) }),
+    }
+}
+
+#[derive(Debug)]
+pub struct CapturedRequest {
+    pub id: i64,
+    pub timestamp: String,
+    pub worker: String,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub params: Vec<u8>,
+    pub response_kind: Option<String>,
+    pub response: Option<Vec<u8>>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub slot: Option<i64>,
+    pub block_hash: Option<String>,
+}
+
+impl CapturedRequest {
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            timestamp: row.get(1),
+            worker: row.get(2),
+            request_id: row.get(3),
+            method: row.get(4),
+            params: row.get(5),
+            response_kind: row.get(6),
+            response: row.get(7),
+            error: row.get(8),
+            duration_ms: row.get(9),
+            slot: row.get(10),
+            block_hash: row.get(11),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "timestamp": self.timestamp,
+            "worker": self.worker,
+            "requestId": self.request_id,
+            "method": self.method,
+            "params": render(&self.params),
+            "responseKind": self.response_kind,
+            "response": self.response.as_deref().map(render),
+            "error": self.error,
+            "durationMs": self.duration_ms,
+            "slot": self.slot,
+            "blockHash": self.block_hash,
+        })
+    }
+}
+
+const CAPTURE_COLUMNS: &str = "id, timestamp::TEXT, worker, request_id, method, params, \
+     response_kind, response, error, duration_ms, slot, block_hash";
+
+/// List the captured requests of a worker, newest first. Only requests with an id lower than
+/// `before` are returned when set, to page backwards.
+pub async fn list_captures(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    worker_id: &str,
+    before: Option<i64>,
+    limit: i64,
+) -> miette::Result<Vec<CapturedRequest>> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let rows = conn
+        .query(
+            &format!(
+                "SELECT {CAPTURE_COLUMNS}
+                 FROM captured_requests
+                 WHERE worker = $1::TEXT AND ($2::BIGINT IS NULL OR id < $2::BIGINT)
+                 ORDER BY id DESC
+                 LIMIT $3::BIGINT"
+            ),
+            &[&worker_id, &before, &limit],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying captured requests")?;
+
+    Ok(rows.iter().map(CapturedRequest::from_row).collect())
+}
+
+pub async fn get_capture(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    id: i64,
+) -> miette::Result<Option<CapturedRequest>> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let row = conn
+        .query_opt(
+            &format!("SELECT {CAPTURE_COLUMNS} FROM captured_requests WHERE id = $1::BIGINT"),
+            &[&id],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying captured request")?;
+
+    Ok(row.as_ref().map(CapturedRequest::from_row))
+}
+
+/// Delete the captures older than the retention period.
+pub async fn purge(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    config: &Config,
+) -> miette::Result<u64> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    conn.execute(
+        "DELETE FROM captured_requests WHERE timestamp < NOW() - make_interval(hours => $1::INT)",
+        &[&(config
+            .capture_retention_hours
+            .unwrap_or(DEFAULT_RETENTION_HOURS) as i32)],
+    )
+    .await
+    .into_diagnostic()
+    .context("deleting captured requests")
+}
+
+/// KV that reads the worker's live data but keeps its writes in memory, so that replays don't
+/// change the worker's state.
+struct ReplayKv {
+    kv: PostgresKv,
+    writes: BTreeMap<(String, String), Payload>,
+}
+
+impl From<&Pool<PostgresConnectionManager<NoTls>>> for ReplayKv {
+    fn from(value: &Pool<PostgresConnectionManager<NoTls>>) -> Self {
+        Self {
+            kv: PostgresKv::from(value),
+            writes: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl KvProvider for ReplayKv {
+    async fn get_value(&mut self, worker_id: &str, key: String) -> Result<Payload, KvError> {
+        match self.writes.get(&(worker_id.to_string(), key.clone())) {
+            Some(value) => Ok(value.clone()),
+            None => self.kv.get_value(worker_id, key).await,
+        }
+    }
+
+    async fn set_value(
+        &mut self,
+        worker_id: &str,
+        key: String,
+        value: Payload,
+    ) -> Result<(), KvError> {
+        self.writes.insert((worker_id.to_string(), key), value);
+        Ok(())
+    }
+
+    async fn list_values(
+        &mut self,
+        worker_id: &str,
+        prefix: String,
+    ) -> Result<Vec<String>, KvError> {
+        let mut keys = self.kv.list_values(worker_id, prefix.clone()).await?;
+        keys.extend(
+            self.writes
+                .keys()
+                .filter(|(worker, key)| worker == worker_id && key.starts_with(&prefix))
+                .map(|(_, key)| key.clone()),
+        );
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+}
+
+/// Signer of replays. Each replay gets throwaway ed25519 keys generated in memory, so replayed
+/// requests can sign like live ones without the worker's keys being used or created, and
+/// without their signatures being valid for the worker's public keys.
+struct ReplaySigner {
+    rng: SystemRandom,
+    keys: HashMap<(String, String), Ed25519KeyPair>,
+}
+impl ReplaySigner {
+    fn new() -> Self {
+        Self {
+            rng: SystemRandom::new(),
+            keys: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl SignerProvider for ReplaySigner {
+    async fn add_key(&mut self, worker_id: &str, key_name: String, algorithm: String) -> Vec<u8> {
+        if algorithm != "ed25519" {
+            warn!(
+                worker_id,
+                key_name, algorithm, "unsupported algorithm in replay"
+            );
+            return vec![];
+        }
+        let Some(pair) = Ed25519KeyPair::generate_pkcs8(&self.rng)
+            .ok()
+            .and_then(|pkcs8| Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).ok())
+        else {
+            warn!(worker_id, key_name, "failed to generate replay key");
+            return vec![];
+        };
+        let public_key = pair.public_key().as_ref().to_vec();
+        self.keys.insert((worker_id.to_string(), key_name), pair);
+        public_key
+    }
+
+    async fn sign_payload(
+        &mut self,
+        worker_id: &str,
+        key_name: String,
+        payload: wit_sign::Payload,
+    ) -> Result<wit_sign::Signature, wit_sign::SignError> {
+        match self.keys.get(&(worker_id.to_string(), key_name.clone())) {
+            Some(pair) => Ok(pair.sign(&payload).as_ref().to_vec()),
+            None => Err(wit_sign::SignError::KeyNotFound(key_name)),
+        }
+    }
+}
+
+/// Outcome of a replayed request.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Replay {
+    pub url: String,
+    pub response_kind: Option<String>,
+    pub response: Option<Value>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    /// Whether the replay answered the same as the captured request.
+    pub same_response: bool,
+}
+
+/// Runtime replays run in, kept between replays so that the ledger connection is reused and
+/// workers are only downloaded and compiled again when their url or config change.
+struct ReplayRuntime {
+    runtime: Runtime,
+    kv: Arc<Mutex<ReplayKv>>,
+    /// Url and config each worker was loaded with.
+    loaded: HashMap<String, (String, Value)>,
+}
+
+/// Runs captured requests against a version of their worker in a runtime of its own. Replays
+/// read the worker's current KV data without writing to it, don't follow the chain and sign with
+/// throwaway keys. They run one at a time.
+#[derive(Clone)]
+pub struct Replayer {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    runtime: Arc<Mutex<Option<ReplayRuntime>>>,
+    ledger: ledgers::u5c::Config,
+    http_client_timeout: Duration,
+    timeout: Duration,
+}
+
+impl Replayer {
+    pub fn new(config: &Config, pool: &Pool<PostgresConnectionManager<NoTls>>) -> Self {
+        Self {
+            pool: pool.clone(),
+            runtime: Default::default(),
+            ledger: config.ledger.clone(),
+            http_client_timeout: Duration::from_secs(config.http_client_timeout.unwrap_or(10)),
+            timeout: Duration::from_secs(
+                config
+                    .request_timeout_seconds
+                    .unwrap_or(server::DEFAULT_REQUEST_TIMEOUT_SECONDS),
+            ),
+        }
+    }
+
+    async fn build_runtime(&self) -> miette::Result<ReplayRuntime> {
+        let kv = Arc::new(Mutex::new(ReplayKv::from(&self.pool)));
+        let ledger = ledgers::u5c::Ledger::new(&self.ledger)
+            .await
+            .into_diagnostic()
+            .context("setting up ledger")?;
+
+        let runtime = Runtime::builder(Store::Custom(Arc::new(Mutex::new(PostgresStore::new(
+            &self.pool,
+            REPLAY_SHARD,
+        )))))
+        .with_ledger(ledger.into())
+        .with_signer(RuntimeSigner::Custom(Arc::new(Mutex::new(
+            ReplaySigner::new(),
+        ))))
+        .with_kv(Kv::Custom(kv.clone()))
+        .with_http(balius_runtime::http::Http::Reqwest(
+            reqwest::Client::builder()
+                .timeout(self.http_client_timeout)
+                .build()
+                .into_diagnostic()
+                .context("building http client")?,
+        ))
+        .build()
+        .into_diagnostic()
+        .context("setting up runtime")?;
+
+        Ok(ReplayRuntime {
+            runtime,
+            kv,
+            loaded: Default::default(),
+        })
+    }
+
+    /// Replay a captured request against the worker loaded from `url` with `config`.
+    pub async fn replay(
+        &self,
+        capture: &CapturedRequest,
+        url: &str,
+        config: Value,
+    ) -> miette::Result<Replay> {
+        let mut guard = self.runtime.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.build_runtime().await?);
+        }
+        let Some(replay) = guard.as_mut() else {
+            unreachable!("the replay runtime is built above");
+        };
+
+        // Writes of earlier replays must not be seen by this one.
+        replay.kv.lock().await.writes.clear();
+
+        let version = (url.to_string(), config);
+        if replay.loaded.get(&capture.worker) != Some(&version) {
+            replay.loaded.remove(&capture.worker);
+            runtime::load_worker(&replay.runtime, &capture.worker, url, version.1.clone())
+                .await
+                .context("loading worker")?;
+            replay.loaded.insert(capture.worker.clone(), version);
+        }
+        let runtime = &replay.runtime;
+
+        let start = Instant::now();
+        let reply =
+            runtime.handle_request(&capture.worker, &capture.method, capture.params.clone());
+        let result = match tokio::time::timeout(self.timeout, reply).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err(format!("request timed out after {:?}", self.timeout)),
+        };
+        let duration_ms = start.elapsed().as_millis() as i64;
+
+        Ok(match result {
+            Ok(response) => {
+                let (kind, bytes) = encode_response(&response);
+                Replay {
+                    url: url.to_string(),
+                    same_response: capture.response_kind.as_deref() == Some(kind)
+                        && capture.response == bytes,
+                    response_kind: Some(kind.to_string()),
+                    response: bytes.as_deref().map(render),
+                    error: None,
+                    duration_ms,
+                }
+            }
+            Err(err) => Replay {
+                url: url.to_string(),
+                same_response: capture.error.is_some(),
+                response_kind: None,
+                response: None,
+                error: Some(err),
+                duration_ms,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_lc_rs::signature::{UnparsedPublicKey, ED25519};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_signer_uses_throwaway_keys() {
+        let mut signer = ReplaySigner::new();
+        let public_key = signer
+            .add_key("ns.worker", "main".into(), "ed25519".into())
+            .await;
+        assert_eq!(public_key.len(), 32);
+
+        let signature = signer
+            .sign_payload("ns.worker", "main".into(), b"payload".to_vec())
+            .await
+            .unwrap();
+        UnparsedPublicKey::new(&ED25519, &public_key)
+            .verify(b"payload", &signature)
+            .unwrap();
+
+        // Every replay gets new keys.
+        let other = ReplaySigner::new()
+            .add_key("ns.worker", "main".into(), "ed25519".into())
+            .await;
+        assert_ne!(other, public_key);
+
+        assert!(matches!(
+            signer
+                .sign_payload("ns.worker", "other".into(), b"payload".to_vec())
+                .await,
+            Err(wit_sign::SignError::KeyNotFound(_))
+        ));
+        assert!(signer
+            .add_key("ns.worker", "secp".into(), "secp256k1".into())
+            .await
+            .is_empty());
+    }
+}
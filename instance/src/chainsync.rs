@@ -2,23 +2,19 @@ use balius_runtime::{drivers, Runtime};
 use kube_leader_election::{LeaseLock, LeaseLockParams};
 use miette::{Context, IntoDiagnostic};
 use operator::kube::Client;
-use std::{
-    sync::{atomic::AtomicBool, Arc},
-    time::Duration,
-};
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
-use super::config::Config;
+use super::{config::Config, health::Flag};
 
 #[instrument("chainsync", skip_all)]
 pub async fn run(
     config: &Config,
     runtime: Runtime,
+    is_leader: Flag,
     cancel: CancellationToken,
 ) -> miette::Result<()> {
-    let is_leader = Arc::new(AtomicBool::new(false));
-
     // Run leader election as background process
     let lease = async {
         let is_leader = is_leader.clone();
@@ -42,7 +38,7 @@ pub async fn run(
             tokio::select! {
                 result = leadership.try_acquire_or_renew() => {
                     match result {
-                        Ok(ll) => is_leader.store(ll.acquired_lease, std::sync::atomic::Ordering::Relaxed),
+                        Ok(ll) => is_leader.set(ll.acquired_lease),
                         Err(err) => tracing::error!("{:?}", err),
                     };
                     tokio::time::sleep(Duration::from_secs(config.lease_renew_seconds.unwrap_or(5))).await;
@@ -60,7 +56,7 @@ pub async fn run(
 
     let chainsync_driver = async {
         loop {
-            if is_leader.load(std::sync::atomic::Ordering::Relaxed) {
+            if is_leader.get() {
                 return tokio::select! {
                     result = drivers::chainsync::run(config.chainsync.clone(), runtime.clone(), cancel.clone()) => {
                         result.into_diagnostic()
@@ -0,0 +1,398 @@
+/// Cleanup of deleted workers.
+///
+///
+/// When a `BaliusWorker` is deleted, it is archived in a table named `deleted_workers`. A
+/// finalizer keeps the resource around until the instance running it has archived it, or until
+/// the operator's finalizer timeout is over when no instance does. Workers with the `delete`
+/// deletion policy are purged once the retention period is over, which deletes their signing
+/// keys along with their `kv`, `logs`, `captured_requests` and `cursors` rows. Workers with the
+/// `retain` policy are kept archived until they are released through the admin API, which purges
+/// them right away. While a worker is archived, its id can't be reused by a new worker, so it
+/// doesn't inherit the old keys and data.
+///
+/// ```sql
+/// CREATE TABLE deleted_workers (
+///     worker VARCHAR(320) NOT NULL,
+///     uid VARCHAR(64) NOT NULL,       -- uid of the deleted BaliusWorker
+///     shard TEXT NOT NULL,
+///     policy VARCHAR(10) NOT NULL,    -- retain or delete
+///     deleted_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+///     purged_at TIMESTAMP WITH TIME ZONE,
+///     PRIMARY KEY (worker, uid)
+/// );
+/// ```
+use std::{sync::Arc, time::Duration};
+
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use miette::{Context, IntoDiagnostic};
+use operator::{
+    kube::{
+        api::{Patch, PatchParams},
+        Api, Client, ResourceExt,
+    },
+    BaliusWorker, DeletionPolicy, ARCHIVE_FINALIZER as FINALIZER,
+};
+use serde_json::json;
+use tokio::sync::Mutex;
+use tokio_postgres::NoTls;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument};
+
+use crate::{capture, config::Config, signer::Signer};
+
+const DEFAULT_RETENTION_DAYS: u32 = 30;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Archive a deleted worker so that it is purged according to its deletion policy.
+pub async fn archive_worker(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    shard: &str,
+    crd: &BaliusWorker,
+) -> miette::Result<()> {
+    let policy = match crd.spec.deletion_policy.clone().unwrap_or_default() {
+        DeletionPolicy::Retain => "retain",
+        DeletionPolicy::Delete => "delete",
+    };
+
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    conn.execute(
+        "INSERT INTO deleted_workers (worker, uid, shard, policy)
+         VALUES ($1::TEXT, $2::TEXT, $3::TEXT, $4::TEXT)
+         ON CONFLICT (worker, uid) DO NOTHING;",
+        &[
+            &crd.worker_id(),
+            &crd.uid().unwrap_or_default(),
+            &shard,
+            &policy,
+        ],
+    )
+    .await
+    .into_diagnostic()
+    .context("archiving worker")?;
+
+    Ok(())
+}
+
+/// Fails if the worker's id still belongs to a deleted worker that hasn't been purged. Deleted
+/// workers archived before ids were namespace-qualified are matched by name.
+pub async fn check_name_available(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    crd: &BaliusWorker,
+) -> miette::Result<()> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let row = conn
+        .query_opt(
+            "SELECT policy FROM deleted_workers
+             WHERE worker IN ($1::TEXT, $2::TEXT) AND uid <> $3::TEXT AND purged_at IS NULL
+             LIMIT 1",
+            &[
+                &crd.worker_id(),
+                &crd.name_any(),
+                &crd.uid().unwrap_or_default(),
+            ],
+        )
+        .await
+        .into_diagnostic()
+        .context("checking deleted workers")?;
+
+    if let Some(row) = row {
+        let policy: String = row.get(0);
+        miette::bail!(
+            "name belongs to a deleted worker whose keys and data are still kept (policy: {policy}), release it through the admin API to reuse it"
+        )
+    }
+
+    // The keys of a purged worker are named after its id until they are deleted from the
+    // backend, a new worker would get the same ones.
+    let pending = conn
+        .query_opt(
+            "SELECT 1 FROM signer_keys WHERE worker LIKE $1::TEXT || '@%' LIMIT 1",
+            &[&crd.worker_id()],
+        )
+        .await
+        .into_diagnostic()
+        .context("checking purged keys")?;
+    if pending.is_some() {
+        miette::bail!("name belongs to a purged worker whose keys are still being deleted");
+    }
+
+    Ok(())
+}
+
+/// Delete the data of an archived worker, including the cursors every shard kept for it, and
+/// mark it as purged. Its signing keys are moved to
+/// [`purged_keys_id`] so that a new worker with the same id doesn't pick them up, and deleted
+/// from the backend by [`delete_purged_keys`] once the transaction is committed.
+async fn purge_worker(
+    txn: &tokio_postgres::Transaction<'_>,
+    worker: &str,
+    uid: &str,
+) -> miette::Result<()> {
+    for statement in [
+        "DELETE FROM kv WHERE worker = $1::TEXT",
+        "DELETE FROM logs WHERE worker = $1::TEXT",
+        "DELETE FROM captured_requests WHERE worker = $1::TEXT",
+        "DELETE FROM cursors WHERE worker = $1::TEXT",
+    ] {
+        txn.execute(statement, &[&worker])
+            .await
+            .into_diagnostic()
+            .context("deleting worker data")?;
+    }
+    txn.execute(
+        "UPDATE signer_keys SET worker = $1::TEXT WHERE worker = $2::TEXT",
+        &[&purged_keys_id(worker, uid), &worker],
+    )
+    .await
+    .into_diagnostic()
+    .context("setting worker keys aside")?;
+    txn.execute(
+        "UPDATE deleted_workers SET purged_at = NOW() WHERE worker = $1::TEXT AND uid = $2::TEXT",
+        &[&worker, &uid],
+    )
+    .await
+    .into_diagnostic()
+    .context("marking worker as purged")?;
+
+    info!(worker, "purged deleted worker");
+    Ok(())
+}
+
+/// Id the signing keys of a purged worker are registered under until they are deleted from the
+/// backend. Worker ids can't contain `@`.
+fn purged_keys_id(worker: &str, uid: &str) -> String {
+    format!("{worker}@{uid}")
+}
+
+/// Delete the signing keys of purged workers from the backend. Keys that fail to be deleted are
+/// retried on the next purge.
+async fn delete_purged_keys(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    signer: &Mutex<Signer>,
+) -> miette::Result<()> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let workers: Vec<String> = conn
+        .query(
+            "SELECT DISTINCT worker FROM signer_keys WHERE worker LIKE '%@%'",
+            &[],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying purged keys")?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    drop(conn);
+
+    for worker in workers {
+        signer
+            .lock()
+            .await
+            .delete_worker_keys(&worker)
+            .await
+            .map_err(|err| miette::miette!("deleting keys for {worker}: {err:?}"))?;
+    }
+    Ok(())
+}
+
+async fn purge(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    signer: &Mutex<Signer>,
+    config: &Config,
+) -> miette::Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let txn = conn
+        .transaction()
+        .await
+        .into_diagnostic()
+        .context("starting transaction")?;
+
+    // Rows are locked so that replicas don't purge the same worker twice.
+    let rows = txn
+        .query(
+            "SELECT worker, uid FROM deleted_workers
+             WHERE shard = $1::TEXT
+               AND policy = 'delete'
+               AND purged_at IS NULL
+               AND deleted_at < NOW() - make_interval(days => $2::INT)
+             FOR UPDATE SKIP LOCKED",
+            &[
+                &config.shard,
+                &(config
+                    .worker_retention_days
+                    .unwrap_or(DEFAULT_RETENTION_DAYS) as i32),
+            ],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying deleted workers")?;
+
+    for row in rows {
+        purge_worker(&txn, row.get(0), row.get(1)).await?;
+    }
+
+    txn.commit()
+        .await
+        .into_diagnostic()
+        .context("committing transaction")?;
+    drop(conn);
+
+    delete_purged_keys(pool, signer).await
+}
+
+/// Purge a deleted worker right away, whatever its deletion policy, so that its id can be used
+/// by a new worker. Returns whether there was an archived worker to purge.
+pub async fn release_worker(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    signer: &Mutex<Signer>,
+    worker: &str,
+) -> miette::Result<bool> {
+    let mut conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let txn = conn
+        .transaction()
+        .await
+        .into_diagnostic()
+        .context("starting transaction")?;
+
+    let rows = txn
+        .query(
+            "SELECT uid FROM deleted_workers
+             WHERE worker = $1::TEXT AND purged_at IS NULL
+             FOR UPDATE",
+            &[&worker],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying deleted workers")?;
+
+    for row in &rows {
+        purge_worker(&txn, worker, row.get(0)).await?;
+    }
+
+    txn.commit()
+        .await
+        .into_diagnostic()
+        .context("committing transaction")?;
+    drop(conn);
+
+    delete_purged_keys(pool, signer).await?;
+    Ok(!rows.is_empty())
+}
+
+/// Keep the worker in the cluster until the instance running it has archived it, so its keys
+/// and data are purged according to its deletion policy even if the deletion happens while the
+/// instance isn't watching.
+pub async fn add_finalizer(client: &Client, crd: &BaliusWorker) -> miette::Result<()> {
+    if crd.finalizers().iter().any(|x| x == FINALIZER) {
+        return Ok(());
+    }
+    let mut finalizers = crd.finalizers().to_vec();
+    finalizers.push(FINALIZER.to_string());
+    patch_finalizers(client, crd, finalizers).await
+}
+
+/// Archive a worker being deleted and let the deletion go through.
+pub async fn finalize_worker(
+    client: &Client,
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    shard: &str,
+    crd: &BaliusWorker,
+) -> miette::Result<()> {
+    if !crd.finalizers().iter().any(|x| x == FINALIZER) {
+        return Ok(());
+    }
+    archive_worker(pool, shard, crd).await?;
+
+    let finalizers = crd
+        .finalizers()
+        .iter()
+        .filter(|x| *x != FINALIZER)
+        .cloned()
+        .collect();
+    patch_finalizers(client, crd, finalizers).await
+}
+
+async fn patch_finalizers(
+    client: &Client,
+    crd: &BaliusWorker,
+    finalizers: Vec<String>,
+) -> miette::Result<()> {
+    let api = Api::<BaliusWorker>::namespaced(client.clone(), &crd.namespace().unwrap_or_default());
+    api.patch(
+        &crd.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(json!({
+            "metadata": {
+                "finalizers": finalizers,
+                // Fails instead of overwriting finalizers changed in the meantime.
+                "resourceVersion": crd.resource_version(),
+            }
+        })),
+    )
+    .await
+    .into_diagnostic()
+    .context("updating finalizers")?;
+    Ok(())
+}
+
+#[instrument("cleanup", skip_all)]
+pub async fn run(
+    config: &Config,
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    signer: Arc<Mutex<Signer>>,
+    cancel: CancellationToken,
+) -> miette::Result<()> {
+    loop {
+        if let Err(err) = purge(&pool, &signer, config).await {
+            error!(err = err.to_string(), "failed to purge deleted workers");
+        }
+        match capture::purge(&pool, config).await {
+            Ok(0) => {}
+            Ok(count) => info!(count, "purged expired captured requests"),
+            Err(err) => error!(err = err.to_string(), "failed to purge captured requests"),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(PURGE_INTERVAL) => {}
+            _ = cancel.cancelled() => {
+                tracing::warn!("received cancellation");
+                return Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purged_keys_id_matches_only_its_worker() {
+        let id = purged_keys_id("ns.worker", "0b9a6f1e");
+        assert_eq!(id, "ns.worker@0b9a6f1e");
+        // Same prefix as the pending keys check in `check_name_available`.
+        assert!(id.starts_with("ns.worker@"));
+        assert!(!purged_keys_id("ns.worker-2", "0b9a6f1e").starts_with("ns.worker@"));
+    }
+}
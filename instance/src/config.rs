@@ -1,9 +1,51 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
 use balius_runtime::{drivers, ledgers};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SignerKind {
+    #[default]
+    Vault,
+    Local,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct KeystoreConfig {
+    /// Keystore file. When not set, keys are kept in the `keystore` table.
+    pub path: Option<PathBuf>,
+    pub passphrase: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum VaultAuth {
+    /// Static token taken from `vault_token`.
+    #[default]
+    Token,
+    /// Login with the pod's service account token.
+    Kubernetes {
+        role: String,
+        mount: Option<String>,
+        jwt_path: Option<PathBuf>,
+    },
+    AppRole {
+        role_id: String,
+        secret_id: String,
+        mount: Option<String>,
+    },
+}
+
+/// Request limits for the workers of a throughput tier.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TierLimits {
+    pub max_in_flight: usize,
+    /// See `request_timeout_seconds`.
+    pub timeout_seconds: Option<u64>,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Config {
     pub network: String,
@@ -19,11 +61,47 @@ pub struct Config {
     pub ledger: ledgers::u5c::Config,
     pub chainsync: drivers::chainsync::Config,
     pub prometheus_addr: SocketAddr,
-    pub vault_address: String,
-    pub vault_token: String,
-    pub vault_token_renew_seconds: u64,
+    /// OTLP gRPC endpoint traces are exported to. Traces are only logged when not set.
+    pub otlp_endpoint: Option<String>,
+    /// Secret the proxy authenticates itself with. Required unless `allow_unauthenticated` is
+    /// set.
+    pub proxy_secret: Option<String>,
+    /// Start without a `proxy_secret`, letting anyone that reaches the instance call its
+    /// workers. Meant for local development.
+    pub allow_unauthenticated: Option<bool>,
+    pub admin_addr: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    pub worker_retention_days: Option<u32>,
+    /// How long captured requests are kept, 72 hours by default.
+    pub capture_retention_hours: Option<u32>,
+    pub signer: Option<SignerKind>,
+    pub keystore: Option<KeystoreConfig>,
+    pub vault_address: Option<String>,
+    /// PEM file with the CA certificate used to verify the vault server.
+    pub vault_ca_cert: Option<PathBuf>,
+    /// Disable verification of the vault server certificate. Only meant for development.
+    pub vault_skip_verify: Option<bool>,
+    /// Vault enterprise namespace the signer operates in.
+    pub vault_namespace: Option<String>,
+    /// Mount path of the transit secrets engine, `transit` by default.
+    pub vault_transit_mount: Option<String>,
+    pub vault_auth: Option<VaultAuth>,
+    pub vault_token: Option<String>,
+    /// Longest time between token renewals, 3600 by default. Tokens are renewed earlier when
+    /// their lease requires it.
+    pub vault_token_renew_seconds: Option<u64>,
+    /// Lease extension requested on each renewal, `1h` by default.
     pub vault_token_renew_increment: Option<String>,
     pub http_client_timeout: Option<u64>,
+    /// Time a worker has to answer a request, 30 by default. It only bounds how long the client
+    /// waits: the runtime doesn't interrupt running wasm, so a worker stuck in a loop keeps its
+    /// thread busy after the timeout. Its in-flight slot is released either way.
+    pub request_timeout_seconds: Option<u64>,
+    pub max_in_flight_requests: Option<usize>,
+    /// Request limits by throughput tier, for tiers that don't use the defaults above.
+    pub tiers: Option<HashMap<String, TierLimits>>,
+    pub log_max_lines_per_second: Option<u32>,
+    pub log_max_message_bytes: Option<usize>,
 }
 
 pub fn load_config<T>(explicit_file: &Option<std::path::PathBuf>) -> Result<T, config::ConfigError>
@@ -0,0 +1,136 @@
+/// Block events of workers, published to SSE subscribers.
+///
+///
+/// Only the instance holding the shard lease runs the chainsync driver, while subscribers can be
+/// connected to any replica. Events are therefore sent through Postgres: the store notifies on
+/// the `block_events` channel once the transaction that moves the workers' cursors has
+/// committed, so they are only published once the block has been applied or undone for the
+/// workers. Blocks are applied to every worker of the shard at once, so each notification is
+/// for a block of a shard rather than of a worker: every replica listens on the channel, keeps
+/// the notifications of its own shard and forwards them to the subscribers of its loaded
+/// workers. Delivery is best effort: a failed notification is logged and dropped.
+use futures_util::StreamExt;
+use miette::{Context, IntoDiagnostic};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::{pin, sync::broadcast};
+use tokio_postgres::{AsyncMessage, NoTls};
+use tokio_util::sync::CancellationToken;
+use tracing::{instrument, warn};
+
+use crate::config::Config;
+
+/// Postgres channel block events are sent through.
+pub const CHANNEL: &str = "block_events";
+
+const CAPACITY: usize = 1024;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BlockEvent {
+    /// The block was applied to the workers.
+    Applied { slot: u64, hash: String },
+    /// The block was undone for the workers.
+    Rollback { slot: u64, hash: String },
+}
+impl BlockEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockEvent::Applied { .. } => "applied",
+            BlockEvent::Rollback { .. } => "rollback",
+        }
+    }
+}
+
+/// Payload of a notification on [`CHANNEL`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub shard: String,
+    pub event: BlockEvent,
+}
+
+/// Fan-out of the block events of this instance's shard.
+#[derive(Clone)]
+pub struct BlockEvents(broadcast::Sender<Arc<BlockEvent>>);
+impl Default for BlockEvents {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+impl BlockEvents {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<BlockEvent>> {
+        self.0.subscribe()
+    }
+
+    fn publish(&self, event: BlockEvent) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.0.send(Arc::new(event));
+    }
+}
+
+async fn listen(connection: &str, shard: &str, events: &BlockEvents) -> miette::Result<()> {
+    let (client, mut connection) = tokio_postgres::connect(connection, NoTls)
+        .await
+        .into_diagnostic()
+        .context("connecting to postgres")?;
+    let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+
+    let subscribe = client.batch_execute(&format!("LISTEN {CHANNEL}"));
+    pin!(subscribe);
+    let mut subscribed = false;
+
+    loop {
+        tokio::select! {
+            result = &mut subscribe, if !subscribed => {
+                result.into_diagnostic().context("listening on channel")?;
+                subscribed = true;
+            }
+            message = messages.next() => match message {
+                Some(Ok(AsyncMessage::Notification(notification))) => {
+                    match serde_json::from_str::<Notification>(notification.payload()) {
+                        Ok(notification) if notification.shard == shard => {
+                            events.publish(notification.event)
+                        }
+                        Ok(_) => {}
+                        Err(err) => warn!(err = err.to_string(), "invalid block event"),
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    return Err(err).into_diagnostic().context("receiving notifications");
+                }
+                None => miette::bail!("connection closed"),
+            }
+        }
+    }
+}
+
+/// Listen for block events and publish them to this instance's subscribers, reconnecting when
+/// the connection is lost.
+#[instrument("events", skip_all)]
+pub async fn run(
+    config: &Config,
+    events: BlockEvents,
+    cancel: CancellationToken,
+) -> miette::Result<()> {
+    let listener = async {
+        loop {
+            if let Err(err) = listen(&config.connection, &config.shard, &events).await {
+                warn!(
+                    err = err.to_string(),
+                    "block events listener failed, reconnecting"
+                );
+            }
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+        }
+    };
+
+    tokio::select! {
+        _ = listener => {}
+        _ = cancel.cancelled() => {
+            warn!("received cancellation, stopping block events listener");
+        }
+    };
+    Ok(())
+}
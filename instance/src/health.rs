@@ -0,0 +1,206 @@
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tokio_postgres::NoTls;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, instrument, warn};
+use url::Url;
+use warp::{http::StatusCode, reply::Reply as _};
+
+use crate::{config::Config, signer::VaultSession};
+
+/// How long each check can take before it is considered failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the ledger is checked in the background.
+const LEDGER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A flag shared between the task that sets it and the health checks.
+#[derive(Clone, Default, Debug)]
+pub struct Flag(Arc<AtomicBool>);
+impl Flag {
+    pub fn set(&self, value: bool) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// State the health checks of the instance look at. Only postgres is checked on each probe, with
+/// a connection from the pool. Vault is reported as last seen by the token renewer and the ledger
+/// as last checked by [`run`], so that probes don't open connections to them.
+#[derive(Clone)]
+pub struct Health {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    vault: Option<VaultSession>,
+    ledger_url: String,
+    /// Whether the ledger's endpoint accepted a connection on the last check.
+    ledger: Flag,
+    /// Whether this pod holds the chainsync lease.
+    pub leader: Flag,
+    /// Whether the CRD watcher has registered the existing workers.
+    pub workers_loaded: Flag,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    postgres: bool,
+    /// `None` when signing with local keys.
+    vault: Option<bool>,
+    ledger: bool,
+    leader: bool,
+    workers_loaded: bool,
+    /// Ready, but some requests are expected to fail.
+    degraded: bool,
+}
+impl Report {
+    /// Not holding the chainsync lease doesn't keep a pod from serving requests. Neither does
+    /// vault being unreachable: it is shared by every replica, taking them all out of the service
+    /// would fail the requests that don't sign too, so the pod is only reported as degraded.
+    fn is_ready(&self) -> bool {
+        self.postgres && self.ledger && self.workers_loaded
+    }
+
+    fn is_degraded(&self) -> bool {
+        self.vault == Some(false)
+    }
+}
+
+impl Health {
+    pub fn new(
+        config: &Config,
+        pool: &Pool<PostgresConnectionManager<NoTls>>,
+        vault: Option<VaultSession>,
+    ) -> Self {
+        Self {
+            pool: pool.clone(),
+            vault,
+            ledger_url: config.ledger.endpoint_url.clone(),
+            ledger: Flag::default(),
+            leader: Flag::default(),
+            workers_loaded: Flag::default(),
+        }
+    }
+
+    async fn check_postgres(&self) -> bool {
+        let check = async {
+            let conn = self.pool.get().await.map_err(|err| err.to_string())?;
+            conn.simple_query("SELECT 1")
+                .await
+                .map_err(|err| err.to_string())
+        };
+        match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => {
+                debug!(err, "postgres health check failed");
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the ledger's endpoint accepts connections.
+    async fn check_ledger(&self) -> bool {
+        let Some(address) = Url::parse(&self.ledger_url).ok().and_then(|url| {
+            Some(format!(
+                "{}:{}",
+                url.host_str()?,
+                url.port_or_known_default()?
+            ))
+        }) else {
+            debug!(url = self.ledger_url, "invalid ledger url");
+            return false;
+        };
+        matches!(
+            tokio::time::timeout(CHECK_TIMEOUT, TcpStream::connect(address)).await,
+            Ok(Ok(_))
+        )
+    }
+
+    async fn report(&self) -> Report {
+        let mut report = Report {
+            postgres: self.check_postgres().await,
+            vault: self.vault.as_ref().map(VaultSession::is_available),
+            ledger: self.ledger.get(),
+            leader: self.leader.get(),
+            workers_loaded: self.workers_loaded.get(),
+            degraded: false,
+        };
+        report.degraded = report.is_degraded();
+        report
+    }
+}
+
+/// Check the ledger every [`LEDGER_CHECK_INTERVAL`] for the health checks to report.
+#[instrument("health", skip_all)]
+pub async fn run(health: Health, cancel: CancellationToken) -> miette::Result<()> {
+    loop {
+        let ledger = health.check_ledger().await;
+        if health.ledger.get() && !ledger {
+            warn!(url = health.ledger_url, "ledger is unreachable");
+        }
+        health.ledger.set(ledger);
+
+        tokio::select! {
+            _ = tokio::time::sleep(LEDGER_CHECK_INTERVAL) => {}
+            _ = cancel.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Liveness: the instance is up. Dependencies are reported but don't fail the check, restarting
+/// the pod wouldn't fix them.
+pub async fn handle_healthz(health: Health) -> warp::reply::Response {
+    warp::reply::json(&health.report().await).into_response()
+}
+
+/// Readiness: the instance can serve its workers.
+pub async fn handle_readyz(health: Health) -> warp::reply::Response {
+    let report = health.report().await;
+    let status = if report.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&report), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(vault: Option<bool>) -> Report {
+        Report {
+            postgres: true,
+            vault,
+            ledger: true,
+            leader: false,
+            workers_loaded: true,
+            degraded: false,
+        }
+    }
+
+    #[test]
+    fn vault_doesnt_gate_readiness() {
+        assert!(report(None).is_ready());
+        assert!(!report(None).is_degraded());
+        assert!(report(Some(true)).is_ready());
+        assert!(!report(Some(true)).is_degraded());
+        assert!(report(Some(false)).is_ready());
+        assert!(report(Some(false)).is_degraded());
+
+        let mut down = report(Some(true));
+        down.postgres = false;
+        assert!(!down.is_ready());
+    }
+}
@@ -7,7 +7,7 @@
 /// ```sql
 ///
 /// CREATE TABLE kv (
-///   worker VARCHAR(255) NOT NULL, -- String column for the worker identifier
+///   worker VARCHAR(320) NOT NULL, -- String column for the worker identifier
 ///   key VARCHAR(255) NOT NULL,    -- String column for the key
 ///   value BYTEA,                  -- Bytea column for binary data (e.g., images, serialized objects)
 ///   PRIMARY KEY (worker, key)     -- Composite primary key on worker and key
@@ -19,6 +19,7 @@ use balius_runtime::{
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
 use tokio_postgres::NoTls;
+use tracing::instrument;
 
 pub struct PostgresKv {
     pool: Pool<PostgresConnectionManager<NoTls>>,
@@ -34,6 +35,7 @@ impl From<&Pool<PostgresConnectionManager<NoTls>>> for PostgresKv {
 
 #[async_trait::async_trait]
 impl KvProvider for PostgresKv {
+    #[instrument("kv.get_value", skip_all, fields(worker = worker_id))]
     async fn get_value(&mut self, worker_id: &str, key: String) -> Result<Payload, KvError> {
         let conn = self
             .pool
@@ -53,6 +55,7 @@ impl KvProvider for PostgresKv {
         }
     }
 
+    #[instrument("kv.set_value", skip_all, fields(worker = worker_id))]
     async fn set_value(
         &mut self,
         worker_id: &str,
@@ -79,6 +82,7 @@ impl KvProvider for PostgresKv {
         }
     }
 
+    #[instrument("kv.list_values", skip_all, fields(worker = worker_id))]
     async fn list_values(
         &mut self,
         worker_id: &str,
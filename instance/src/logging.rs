@@ -1,7 +1,7 @@
-/// Postgres backend for Key Value interface.
+/// Postgres backend for Logging interface.
 ///
 ///
-/// This expects to be connected to a DB that has a table named `kv`, which should be created
+/// This expects to be connected to a DB that has a table named `logs`, which should be created
 /// using the following insert statement:
 ///
 /// ```sql
@@ -9,29 +9,150 @@
 /// CREATE TABLE logs (
 ///     id BIGSERIAL PRIMARY KEY,
 ///     timestamp TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
-///     worker VARCHAR(100) NOT NULL,
+///     worker VARCHAR(320) NOT NULL,
 ///     level VARCHAR(50) NOT NULL, -- e.g., INFO, WARN, ERROR, DEBUG
 ///     message TEXT NOT NULL,
-///     context TEXT NOT NULL
+///     context TEXT NOT NULL,
+///     context_json JSONB,     -- context, when it parses as JSON
+///     slot BIGINT,            -- slot of the block being applied when the line was emitted
+///     request_id TEXT         -- JSON-RPC request id when the line was emitted by a request
 /// );
+///
+/// CREATE INDEX logs_worker_slot_idx ON logs (worker, slot);
+/// CREATE INDEX logs_worker_request_id_idx ON logs (worker, request_id);
+/// CREATE INDEX logs_context_json_idx ON logs USING GIN (context_json jsonb_path_ops);
+/// ```
+///
+/// Existing deployments are migrated by `migrations/20261019-01.sql`.
+///
+/// Each worker is allowed a limited amount of lines per second and bytes per message. Lines over
+/// the rate limit are dropped and summarized in a single `WARN` row once their window is over,
+/// and oversized messages and contexts are truncated. Buffered lines are written every
+/// [`FLUSH_INTERVAL`], or sooner when the buffer fills up.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
 use balius_runtime::{logging::LoggerProvider, wit::balius::app::logging::Level};
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
 use chrono::{DateTime, Utc};
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use tokio::sync::Mutex;
 use tokio_postgres::NoTls;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+const DEFAULT_MAX_LINES_PER_SECOND: u32 = 100;
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 4096;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+tokio::task_local! {
+    /// Id of the JSON-RPC request being handled by the current task, if any.
+    pub static REQUEST_ID: Option<String>;
+}
+
+/// Slot of the block currently being applied by the chainsync driver. Updated by the store when
+/// a block is written ahead, and attached to the log lines that are not emitted by a request.
+#[derive(Clone, Default, Debug)]
+pub struct CurrentSlot(Arc<AtomicU64>);
+impl CurrentSlot {
+    pub fn set(&self, slot: u64) {
+        self.0.store(slot, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            slot => Some(slot),
+        }
+    }
+}
 
 struct LogRow {
     pub timestamp: DateTime<Utc>,
     pub worker: String,
     pub level: String,
     pub context: String,
+    pub context_json: Option<String>,
     pub message: String,
+    pub slot: Option<i64>,
+    pub request_id: Option<String>,
+}
+impl LogRow {
+    fn new(worker: &str, level: &str, context: String, message: String, slot: Option<u64>) -> Self {
+        let request_id = REQUEST_ID.try_with(|id| id.clone()).ok().flatten();
+        let context_json = serde_json::from_str::<serde_json::Value>(&context)
+            .ok()
+            .map(|value| value.to_string());
+
+        Self {
+            timestamp: Utc::now(),
+            worker: worker.to_string(),
+            level: level.to_string(),
+            context,
+            context_json,
+            message,
+            // Lines emitted while handling a request are not tied to the block being applied.
+            slot: match request_id {
+                Some(_) => None,
+                None => slot.map(|x| x as i64),
+            },
+            request_id,
+        }
+    }
+}
+
+struct WorkerBudget {
+    window_start: Instant,
+    lines: u32,
+    dropped: u64,
+}
+impl Default for WorkerBudget {
+    fn default() -> Self {
+        Self {
+            window_start: Instant::now(),
+            lines: 0,
+            dropped: 0,
+        }
+    }
+}
+
+struct LoggerMetrics {
+    dropped_lines: Counter<u64>,
+    truncated_lines: Counter<u64>,
+}
+impl Default for LoggerMetrics {
+    fn default() -> Self {
+        let meter = global::meter("baliusd");
+        Self {
+            dropped_lines: meter
+                .u64_counter("worker_log_dropped_lines")
+                .with_description("Worker log lines dropped because of the rate limit")
+                .build(),
+            truncated_lines: meter
+                .u64_counter("worker_log_truncated_lines")
+                .with_description("Worker log lines truncated because of the size limit")
+                .build(),
+        }
+    }
 }
 
 pub struct PostgresLogger {
     pool: Pool<PostgresConnectionManager<NoTls>>,
     buffer: Vec<LogRow>,
     buffer_size: usize,
+    max_lines_per_second: u32,
+    max_message_bytes: usize,
+    budgets: HashMap<String, WorkerBudget>,
+    metrics: LoggerMetrics,
+    current_slot: CurrentSlot,
 }
 impl From<&Pool<PostgresConnectionManager<NoTls>>> for PostgresLogger {
     fn from(value: &Pool<PostgresConnectionManager<NoTls>>) -> Self {
@@ -39,46 +160,159 @@ impl From<&Pool<PostgresConnectionManager<NoTls>>> for PostgresLogger {
             pool: value.clone(),
             buffer: Vec::with_capacity(1024),
             buffer_size: 1024,
+            max_lines_per_second: DEFAULT_MAX_LINES_PER_SECOND,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            budgets: HashMap::new(),
+            metrics: LoggerMetrics::default(),
+            current_slot: CurrentSlot::default(),
         }
     }
 }
 
+fn truncation_suffix(bytes: usize) -> String {
+    format!("... ({bytes} bytes truncated)")
+}
+
+/// Cut `value` down to at most `max_bytes` including the truncation suffix, respecting char
+/// boundaries. Returns whether the value was truncated.
+fn truncate(value: &mut String, max_bytes: usize) -> bool {
+    if value.len() <= max_bytes {
+        return false;
+    }
+
+    // Less than the whole value is cut, so the suffix is never longer than this one.
+    let original = value.len();
+    let mut end = max_bytes.saturating_sub(truncation_suffix(original).len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(&truncation_suffix(original - end));
+    true
+}
+
 impl PostgresLogger {
+    pub fn with_limits(
+        mut self,
+        max_lines_per_second: Option<u32>,
+        max_message_bytes: Option<usize>,
+    ) -> Self {
+        if let Some(max_lines_per_second) = max_lines_per_second {
+            self.max_lines_per_second = max_lines_per_second;
+        }
+        if let Some(max_message_bytes) = max_message_bytes {
+            self.max_message_bytes = max_message_bytes;
+        }
+        self
+    }
+
+    pub fn with_current_slot(mut self, current_slot: CurrentSlot) -> Self {
+        self.current_slot = current_slot;
+        self
+    }
+
     fn should_flush(&self) -> bool {
         self.buffer.len() >= self.buffer_size
     }
 
+    fn dropped_summary(&self, worker_id: &str, dropped: u64) -> LogRow {
+        LogRow::new(
+            worker_id,
+            "WARN",
+            "logger".to_string(),
+            format!("{dropped} lines dropped"),
+            self.current_slot.get(),
+        )
+    }
+
+    /// Account for a new line from `worker_id`. Returns false if the line should be dropped.
+    /// When a new window starts, a summary row for the lines dropped on the previous one is
+    /// pushed to the buffer.
+    fn admit(&mut self, worker_id: &str) -> bool {
+        let budget = self.budgets.entry(worker_id.to_string()).or_default();
+
+        if budget.window_start.elapsed() >= RATE_WINDOW {
+            let dropped = budget.dropped;
+            *budget = WorkerBudget::default();
+
+            if dropped > 0 {
+                let row = self.dropped_summary(worker_id, dropped);
+                self.buffer.push(row);
+            }
+        }
+
+        let budget = self.budgets.get_mut(worker_id).unwrap();
+        if budget.lines >= self.max_lines_per_second {
+            budget.dropped += 1;
+            self.metrics
+                .dropped_lines
+                .add(1, &[KeyValue::new("worker", worker_id.to_string())]);
+            return false;
+        }
+
+        budget.lines += 1;
+        true
+    }
+
+    /// Push the summary rows of the windows that are over, so drops at the end of a burst are
+    /// reported without waiting for the worker to log again. Idle workers are forgotten.
+    fn summarize_dropped(&mut self) {
+        let mut summaries = vec![];
+        self.budgets.retain(|worker_id, budget| {
+            if budget.window_start.elapsed() < RATE_WINDOW {
+                return true;
+            }
+            if budget.dropped > 0 {
+                summaries.push((worker_id.clone(), budget.dropped));
+            }
+            false
+        });
+
+        for (worker_id, dropped) in summaries {
+            let row = self.dropped_summary(&worker_id, dropped);
+            self.buffer.push(row);
+        }
+    }
+
     async fn flush(&mut self) {
         if self.buffer.is_empty() {
             return;
         }
 
         let mut sql = String::new();
-        sql.push_str("INSERT INTO logs (timestamp, worker, level, context, message) VALUES ");
+        sql.push_str(
+            "INSERT INTO logs (timestamp, worker, level, context, context_json, message, slot, request_id) VALUES ",
+        );
 
         let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> =
-            Vec::with_capacity(self.buffer.len() * 5);
+            Vec::with_capacity(self.buffer.len() * 8);
 
         for (i, row) in self.buffer.iter().enumerate() {
             if i > 0 {
                 sql.push(',');
             }
 
-            let base = i * 5;
+            let base = i * 8;
             sql.push_str(&format!(
-                "(${}::TIMESTAMPTZ, ${}::TEXT, ${}::TEXT, ${}::TEXT, ${}::TEXT)",
+                "(${}::TIMESTAMPTZ, ${}::TEXT, ${}::TEXT, ${}::TEXT, ${}::TEXT::JSONB, ${}::TEXT, ${}::BIGINT, ${}::TEXT)",
                 base + 1,
                 base + 2,
                 base + 3,
                 base + 4,
-                base + 5
+                base + 5,
+                base + 6,
+                base + 7,
+                base + 8
             ));
 
             params.push(&row.timestamp);
             params.push(&row.worker);
             params.push(&row.level);
             params.push(&row.context);
+            params.push(&row.context_json);
             params.push(&row.message);
+            params.push(&row.slot);
+            params.push(&row.request_id);
         }
 
         let conn = match self.pool.get().await {
@@ -99,7 +333,13 @@ impl PostgresLogger {
 
 #[async_trait::async_trait]
 impl LoggerProvider for PostgresLogger {
-    async fn log(&mut self, worker_id: &str, level: Level, context: String, message: String) {
+    async fn log(
+        &mut self,
+        worker_id: &str,
+        level: Level,
+        mut context: String,
+        mut message: String,
+    ) {
         let level = match level {
             Level::Info => "INFO",
             Level::Debug => "DEBUG",
@@ -109,13 +349,25 @@ impl LoggerProvider for PostgresLogger {
             Level::Trace => return,
         };
 
-        let row = LogRow {
-            timestamp: Utc::now(),
-            worker: worker_id.to_string(),
-            level: level.to_string(),
-            context,
-            message,
-        };
+        if !self.admit(worker_id) {
+            return;
+        }
+
+        let context_bytes = context.len();
+        let truncated_context = truncate(&mut context, self.max_message_bytes);
+        let truncated_message = truncate(&mut message, self.max_message_bytes);
+        if truncated_context || truncated_message {
+            self.metrics
+                .truncated_lines
+                .add(1, &[KeyValue::new("worker", worker_id.to_string())]);
+        }
+
+        let mut row = LogRow::new(worker_id, level, context, message, self.current_slot.get());
+        // A cut JSON context no longer parses, keep something queryable in its place.
+        if truncated_context {
+            row.context_json =
+                Some(serde_json::json!({ "truncated": true, "bytes": context_bytes }).to_string());
+        }
 
         self.buffer.push(row);
 
@@ -124,3 +376,137 @@ impl LoggerProvider for PostgresLogger {
         }
     }
 }
+
+/// Write the buffered lines and the pending drop summaries every [`FLUSH_INTERVAL`], and once
+/// more on shutdown.
+#[instrument("logger", skip_all)]
+pub async fn run(
+    logger: Arc<Mutex<PostgresLogger>>,
+    cancel: CancellationToken,
+) -> miette::Result<()> {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        let cancelled = tokio::select! {
+            _ = interval.tick() => false,
+            _ = cancel.cancelled() => true,
+        };
+
+        let mut logger = logger.lock().await;
+        logger.summarize_dropped();
+        logger.flush().await;
+
+        if cancelled {
+            tracing::warn!("received cancellation, flushed worker logs");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger(max_lines_per_second: u32) -> PostgresLogger {
+        let manager = PostgresConnectionManager::new("host=localhost".parse().unwrap(), NoTls);
+        PostgresLogger::from(&Pool::builder().build_unchecked(manager))
+            .with_limits(Some(max_lines_per_second), None)
+    }
+
+    /// Move the worker's window to the past, as if it was over.
+    fn end_window(logger: &mut PostgresLogger, worker_id: &str) {
+        logger.budgets.get_mut(worker_id).unwrap().window_start =
+            Instant::now().checked_sub(RATE_WINDOW).unwrap();
+    }
+
+    #[test]
+    fn truncate_respects_char_boundaries() {
+        // The suffix takes 24 bytes, leaving 2 for the value, which cuts through `é`.
+        let mut value = format!("aé{}", "b".repeat(40));
+        assert!(truncate(&mut value, 26));
+        assert_eq!(value, "a... (42 bytes truncated)");
+
+        let mut value = "abc".to_string();
+        assert!(!truncate(&mut value, 3));
+        assert_eq!(value, "abc");
+    }
+
+    #[tokio::test]
+    async fn truncated_context_stays_json() {
+        let mut logger = logger(10).with_limits(None, Some(64));
+        let context = serde_json::json!({ "data": "x".repeat(100) }).to_string();
+        let bytes = context.len();
+        logger
+            .log("ns.a", Level::Info, context, "message".into())
+            .await;
+
+        let row = logger.buffer.last().unwrap();
+        assert!(row.context.len() <= 64);
+        let context_json: serde_json::Value =
+            serde_json::from_str(row.context_json.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            context_json,
+            serde_json::json!({ "truncated": true, "bytes": bytes })
+        );
+    }
+
+    #[test]
+    fn truncate_keeps_the_suffix_within_the_limit() {
+        for max_bytes in [30, 100, 4096] {
+            let mut value = "x".repeat(10_000);
+            assert!(truncate(&mut value, max_bytes));
+            assert!(value.len() <= max_bytes, "{} > {max_bytes}", value.len());
+            assert!(value.ends_with("bytes truncated)"));
+        }
+    }
+
+    #[tokio::test]
+    async fn admit_drops_lines_over_budget() {
+        let mut logger = logger(2);
+
+        assert!(logger.admit("ns.a"));
+        assert!(logger.admit("ns.a"));
+        assert!(!logger.admit("ns.a"));
+        assert!(!logger.admit("ns.a"));
+        // Budgets are per worker.
+        assert!(logger.admit("ns.b"));
+
+        assert_eq!(logger.budgets["ns.a"].dropped, 2);
+        assert!(logger.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn admit_summarizes_drops_when_window_starts() {
+        let mut logger = logger(1);
+        assert!(logger.admit("ns.a"));
+        assert!(!logger.admit("ns.a"));
+
+        end_window(&mut logger, "ns.a");
+        assert!(logger.admit("ns.a"));
+
+        assert_eq!(logger.buffer.len(), 1);
+        assert_eq!(logger.buffer[0].level, "WARN");
+        assert_eq!(logger.buffer[0].message, "1 lines dropped");
+        assert_eq!(logger.budgets["ns.a"].dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn summarize_dropped_reports_ended_windows_only() {
+        let mut logger = logger(1);
+        for worker in ["ns.a", "ns.b", "ns.c"] {
+            assert!(logger.admit(worker));
+        }
+        assert!(!logger.admit("ns.a"));
+        assert!(!logger.admit("ns.b"));
+        end_window(&mut logger, "ns.a");
+        end_window(&mut logger, "ns.c");
+
+        logger.summarize_dropped();
+
+        assert_eq!(logger.buffer.len(), 1);
+        assert_eq!(logger.buffer[0].worker, "ns.a");
+        // The window of `ns.b` is still open, idle `ns.c` is forgotten.
+        assert!(logger.budgets.contains_key("ns.b"));
+        assert!(!logger.budgets.contains_key("ns.a"));
+        assert!(!logger.budgets.contains_key("ns.c"));
+    }
+}
@@ -1,22 +1,32 @@
 use balius_runtime::{ledgers, Runtime, Store};
 use kv::PostgresKv;
-use logging::PostgresLogger;
-use metrics::init_meter_provider;
+use logging::{CurrentSlot, PostgresLogger};
+use metrics::{init_meter_provider, init_tracer_provider};
 use miette::{Context, IntoDiagnostic as _};
+use opentelemetry::trace::TracerProvider as _;
 use prometheus::Registry;
 use runtime::FailedWorkers;
-use signer::VaultSigner;
+use signer::Signer;
 use std::{str::FromStr, sync::Arc, time::Duration};
 use store::PostgresStore;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn, Level};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt as _, util::SubscriberInitExt as _,
+};
 
+mod admin;
+mod capture;
 mod chainsync;
+mod cleanup;
 mod config;
+mod events;
+mod health;
 mod kv;
 mod logging;
 mod metrics;
+mod migration;
 mod runtime;
 mod server;
 mod signer;
@@ -63,14 +73,24 @@ async fn main() -> miette::Result<()> {
     let config: config::Config = config::load_config(&None)
         .into_diagnostic()
         .context("loading config")?;
+    if config.proxy_secret.is_none() && !config.allow_unauthenticated.unwrap_or_default() {
+        miette::bail!("proxy_secret must be set, or allow_unauthenticated to run without it");
+    }
 
-    tracing_subscriber::fmt()
-        .with_max_level(
+    let tracer_provider = init_tracer_provider(&config)?;
+    tracing_subscriber::registry()
+        .with(LevelFilter::from_level(
             config
                 .logging_level
                 .as_ref()
                 .map(|x| Level::from_str(x).expect("Invalid logging level"))
                 .unwrap_or(Level::INFO),
+        ))
+        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracer_provider
+                .as_ref()
+                .map(|x| tracing_opentelemetry::layer().with_tracer(x.tracer("baliusd"))),
         )
         .init();
 
@@ -88,28 +108,46 @@ async fn main() -> miette::Result<()> {
         .into_diagnostic()
         .context("failed to build pool")?;
 
-    let store = Store::Custom(Arc::new(Mutex::new(PostgresStore::new(
-        &pool,
-        &config.shard,
-    ))));
+    let current_slot = CurrentSlot::default();
+    let store = Store::Custom(Arc::new(Mutex::new(
+        PostgresStore::new(&pool, &config.shard).with_current_slot(current_slot.clone()),
+    )));
 
     let ledger = ledgers::u5c::Ledger::new(&config.ledger)
         .await
         .into_diagnostic()
         .context("setting up ledger")?;
 
-    let failed = FailedWorkers::default();
+    let signer = Signer::try_new(&config, &pool).await?;
+    let vault_session = signer.vault_session();
+    let health = health::Health::new(&config, &pool, vault_session.clone());
+    let signing = signer.worker_signing();
+    let signer = Arc::new(Mutex::new(signer));
+
+    let workers = runtime::Workers {
+        loaded: Default::default(),
+        failed: FailedWorkers::default(),
+        signing,
+        limits: server::WorkerLimits::new(&config),
+        openrpc: Default::default(),
+        capture: capture::RequestCapture::new(&pool, &config.shard),
+    };
+    let block_events = events::BlockEvents::default();
+    let logger = Arc::new(Mutex::new(
+        PostgresLogger::from(&pool)
+            .with_limits(
+                config.log_max_lines_per_second,
+                config.log_max_message_bytes,
+            )
+            .with_current_slot(current_slot.clone()),
+    ));
     let runtime = Runtime::builder(store)
         .with_ledger(ledger.into())
-        .with_signer(balius_runtime::sign::Signer::Custom(Arc::new(Mutex::new(
-            VaultSigner::try_new(&config.vault_address, &config.vault_token)?,
-        ))))
+        .with_signer(balius_runtime::sign::Signer::Custom(signer.clone()))
         .with_kv(balius_runtime::kv::Kv::Custom(Arc::new(Mutex::new(
             PostgresKv::from(&pool),
         ))))
-        .with_logger(balius_runtime::logging::Logger::Custom(Arc::new(
-            Mutex::new(PostgresLogger::from(&pool)),
-        )))
+        .with_logger(balius_runtime::logging::Logger::Custom(logger.clone()))
         .with_http(balius_runtime::http::Http::Reqwest(
             reqwest::Client::builder()
                 .timeout(Duration::from_secs(
@@ -122,13 +160,21 @@ async fn main() -> miette::Result<()> {
         .into_diagnostic()
         .context("setting up runtime")?;
 
+    let kube_client = operator::kube::Client::try_default()
+        .await
+        .into_diagnostic()
+        .context("creating kube client")?;
+
     let cancel = hook_exit_token();
 
     let jsonrpc_server = async {
         server::serve(
             config.rpc.clone(),
             runtime.clone(),
-            failed.clone(),
+            workers.clone(),
+            signer.clone(),
+            block_events.clone(),
+            config.proxy_secret.clone(),
             cancel.clone(),
         )
         .await
@@ -136,12 +182,33 @@ async fn main() -> miette::Result<()> {
         .context("Running JsonRPC server")
     };
 
-    let token_renewer = signer::run(&config, cancel.clone());
-    let chainsync_driver = chainsync::run(&config, runtime.clone(), cancel.clone());
+    let token_renewer = signer::run(&config, vault_session, cancel.clone());
+    let cleanup = cleanup::run(&config, pool.clone(), signer.clone(), cancel.clone());
+    let admin_server = admin::serve(
+        &config,
+        admin::AdminState {
+            signer: signer.clone(),
+            pool: pool.clone(),
+            client: kube_client.clone(),
+            workers: workers.clone(),
+            shard: config.shard.clone(),
+            replayer: capture::Replayer::new(&config, &pool),
+        },
+        cancel.clone(),
+    );
+    let log_flusher = logging::run(logger.clone(), cancel.clone());
+    let capture_writer = capture::run(workers.capture.clone(), cancel.clone());
+    let events_listener = events::run(&config, block_events.clone(), cancel.clone());
+    let chainsync_driver = chainsync::run(
+        &config,
+        runtime.clone(),
+        health.leader.clone(),
+        cancel.clone(),
+    );
 
     let runtime_update = async {
         tokio::select! {
-            _ = runtime::update_runtime(&config, runtime.clone(), failed.clone()) => {
+            _ = runtime::update_runtime(&config, runtime.clone(), workers.clone(), signer.clone(), pool.clone(), health.workers_loaded.clone()) => {
 
             }
             _ = cancel.cancelled() => {
@@ -151,9 +218,11 @@ async fn main() -> miette::Result<()> {
         Ok(())
     };
 
+    let health_checks = health::run(health.clone(), cancel.clone());
+
     let metrics_server = async {
         tokio::select! {
-            _ = metrics::run(&config, registry.clone()) => {
+            _ = metrics::run(&config, registry.clone(), health.clone()) => {
 
             }
             _ = cancel.cancelled() => {
@@ -168,7 +237,20 @@ async fn main() -> miette::Result<()> {
         chainsync_driver,
         runtime_update,
         metrics_server,
-        token_renewer
+        health_checks,
+        token_renewer,
+        admin_server,
+        cleanup,
+        events_listener,
+        log_flusher,
+        capture_writer
     )?;
+
+    if let Some(provider) = tracer_provider {
+        provider
+            .shutdown()
+            .into_diagnostic()
+            .context("flushing traces")?;
+    }
     Ok(())
 }
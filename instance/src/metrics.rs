@@ -1,22 +1,42 @@
-use miette::IntoDiagnostic;
+use miette::{Context, IntoDiagnostic};
 use opentelemetry::global;
-use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    metrics::SdkMeterProvider, propagation::TraceContextPropagator, trace::SdkTracerProvider,
+    Resource,
+};
 use prometheus::{Encoder, Registry};
 use tracing::{info, instrument};
 use warp::{reply::Reply, Filter};
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    health::{handle_healthz, handle_readyz, Health},
+};
 
 #[instrument("metrics", skip_all)]
-pub async fn run(config: &Config, registry: Registry) -> miette::Result<()> {
+pub async fn run(config: &Config, registry: Registry, health: Health) -> miette::Result<()> {
     info!(
         addr = config.prometheus_addr.to_string(),
         "Started metrics server"
     );
-    let route = warp::path!("metrics")
+    let metrics = warp::path!("metrics")
         .map(move || registry.clone())
         .then(metrics_handler);
 
+    let healthz = warp::path!("healthz")
+        .map({
+            let health = health.clone();
+            move || health.clone()
+        })
+        .then(handle_healthz);
+
+    let readyz = warp::path!("readyz")
+        .map(move || health.clone())
+        .then(handle_readyz);
+
+    let route = metrics.or(healthz).or(readyz);
+
     warp::serve(route).run(config.prometheus_addr).await;
 
     Ok(())
@@ -33,6 +53,29 @@ pub fn init_meter_provider(registry: Registry) -> miette::Result<()> {
     Ok(())
 }
 
+/// Set up the export of traces to `otlp_endpoint`, if configured. Trace context is propagated
+/// with W3C `traceparent` headers either way.
+pub fn init_tracer_provider(config: &Config) -> miette::Result<Option<SdkTracerProvider>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let Some(endpoint) = config.otlp_endpoint.as_ref() else {
+        return Ok(None);
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .into_diagnostic()
+        .context("building otlp exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("baliusd").build())
+        .build();
+
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
 async fn metrics_handler(registry: Registry) -> impl Reply {
     let encoder = prometheus::TextEncoder::new();
 
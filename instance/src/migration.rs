@@ -0,0 +1,117 @@
+/// Migration of workers to namespace-qualified ids.
+///
+/// ```sql
+/// CREATE TABLE legacy_workers (
+///     legacy VARCHAR(255) PRIMARY KEY,
+///     worker VARCHAR(320) NOT NULL UNIQUE,
+///     migrated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+/// );
+/// ```
+///
+/// Workers used to be identified by their name only, so two `BaliusWorker`s with the same name
+/// in different namespaces shared their `kv`, `logs` and `cursors` rows and their signing keys.
+/// They are now identified by `{namespace}.{name}`. Nothing can tell which namespace the rows
+/// under a bare name belong to, so they are only handed over when an operator asks for it
+/// through the admin API, naming the worker that inherits them. Each bare name is handed over
+/// once, recorded in `legacy_workers`.
+///
+/// Signing keys keep their name in the backend, which is recorded in `signer_keys`, so only the
+/// registry rows are moved. Keys created before `signer_keys` existed have no registry row, the
+/// signer finds them under their legacy name when the worker that inherited them registers them.
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use operator::parse_worker_id;
+use tokio_postgres::NoTls;
+use tracing::info;
+
+const TABLES: [&str; 5] = ["kv", "logs", "cursors", "signer_keys", "signatures"];
+/// Tables keyed by worker, where the worker's own rows could clash with the legacy ones.
+const KEYED_TABLES: [&str; 3] = ["kv", "cursors", "signer_keys"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("invalid worker id: {0}")]
+    InvalidWorker(String),
+    #[error("legacy worker {0} was already migrated to {1}")]
+    AlreadyMigrated(String, String),
+    #[error("worker {0} already has rows in {1}, migrate it before it runs")]
+    Conflict(String, String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl From<tokio_postgres::Error> for MigrationError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        Self::Internal(err.to_string())
+    }
+}
+
+/// Move the rows kept under the worker's bare name to its namespace-qualified id. Returns the
+/// number of rows moved.
+pub async fn migrate_legacy_worker(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    worker: &str,
+) -> Result<u64, MigrationError> {
+    let Some((_, legacy)) = parse_worker_id(worker) else {
+        return Err(MigrationError::InvalidWorker(worker.to_string()));
+    };
+
+    let mut conn = pool
+        .get()
+        .await
+        .map_err(|err| MigrationError::Internal(err.to_string()))?;
+    let txn = conn.transaction().await?;
+
+    // Concurrent requests for the same name wait on each other here, only the first one moves
+    // the rows. The claim is rolled back along with the rest if the migration fails.
+    let claimed = txn
+        .execute(
+            "INSERT INTO legacy_workers (legacy, worker) VALUES ($1::TEXT, $2::TEXT)
+             ON CONFLICT DO NOTHING",
+            &[&legacy, &worker],
+        )
+        .await?;
+    if claimed == 0 {
+        let row = txn
+            .query_one(
+                "SELECT worker FROM legacy_workers WHERE legacy = $1::TEXT",
+                &[&legacy],
+            )
+            .await?;
+        return Err(MigrationError::AlreadyMigrated(
+            legacy.to_string(),
+            row.get(0),
+        ));
+    }
+
+    for table in KEYED_TABLES {
+        let existing = txn
+            .query_opt(
+                &format!("SELECT 1 FROM {table} WHERE worker = $1::TEXT LIMIT 1"),
+                &[&worker],
+            )
+            .await?;
+        if existing.is_some() {
+            return Err(MigrationError::Conflict(
+                worker.to_string(),
+                table.to_string(),
+            ));
+        }
+    }
+
+    let mut moved = 0;
+    for table in TABLES {
+        moved += txn
+            .execute(
+                &format!("UPDATE {table} SET worker = $1::TEXT WHERE worker = $2::TEXT"),
+                &[&worker, &legacy],
+            )
+            .await?;
+    }
+
+    txn.commit().await?;
+
+    info!(worker, legacy, moved, "migrated legacy worker rows");
+
+    Ok(moved)
+}
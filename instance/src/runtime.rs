@@ -1,7 +1,11 @@
 use std::{collections::HashMap, sync::Arc};
 
+use aws_lc_rs::digest;
 use aws_sdk_s3::Client as S3Client;
 use balius_runtime::Runtime;
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::Utc;
 use futures_util::TryStreamExt;
 use miette::{Context, IntoDiagnostic};
 use operator::{
@@ -9,14 +13,27 @@ use operator::{
         runtime::watcher::{self, Config as ConfigWatcher, Event},
         Api, Client, CustomResourceExt, ResourceExt,
     },
-    patch_resource_status, BaliusWorker,
+    parse_worker_id, patch_resource_status, BaliusWorker,
 };
+use serde::Serialize;
 use serde_json::Value;
-use tokio::{pin, sync::RwLock};
+use tokio::{
+    pin,
+    sync::{Mutex, RwLock},
+};
+use tokio_postgres::NoTls;
 use tracing::{error, info, instrument};
 use url::Url;
 
-use crate::{config::Config, utils::handle_legacy_networks};
+use crate::{
+    capture::RequestCapture,
+    cleanup,
+    config::Config,
+    health::Flag,
+    server::WorkerLimits,
+    signer::{Signer, WorkerSigning},
+    utils::handle_legacy_networks,
+};
 
 #[derive(Default, Clone, Debug)]
 pub struct FailedWorkers(Arc<RwLock<HashMap<String, String>>>);
@@ -35,6 +52,106 @@ impl FailedWorkers {
     pub async fn read(&self, worker_id: &str) -> Option<String> {
         self.0.read().await.get(worker_id).map(|x| x.to_owned())
     }
+
+    pub async fn list(&self) -> HashMap<String, String> {
+        self.0.read().await.clone()
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadedWorker {
+    pub url: String,
+    #[serde(skip)]
+    pub config: Value,
+    /// Hex encoded sha256 of the worker's config.
+    pub config_hash: String,
+    pub loaded_at: String,
+}
+
+/// Workers successfully registered in the runtime.
+#[derive(Default, Clone, Debug)]
+pub struct LoadedWorkers(Arc<RwLock<HashMap<String, LoadedWorker>>>);
+impl LoadedWorkers {
+    async fn add(&self, worker_id: &str, crd: &BaliusWorker) {
+        let config = Value::Object(crd.spec.config.clone());
+        let config_hash = hex::encode(digest::digest(
+            &digest::SHA256,
+            config.to_string().as_bytes(),
+        ));
+        self.0.write().await.insert(
+            worker_id.to_string(),
+            LoadedWorker {
+                url: crd.spec.url.clone(),
+                config,
+                config_hash,
+                loaded_at: Utc::now().to_rfc3339(),
+            },
+        );
+    }
+
+    async fn remove(&self, worker_id: &str) {
+        self.0.write().await.remove(worker_id);
+    }
+
+    pub async fn list(&self) -> HashMap<String, LoadedWorker> {
+        self.0.read().await.clone()
+    }
+
+    pub async fn get(&self, worker_id: &str) -> Option<LoadedWorker> {
+        self.0.read().await.get(worker_id).cloned()
+    }
+
+    pub async fn contains(&self, worker_id: &str) -> bool {
+        self.0.read().await.contains_key(worker_id)
+    }
+}
+
+/// OpenRPC documents referenced by the workers' specs, answered to `rpc.discover`.
+#[derive(Default, Clone, Debug)]
+pub struct OpenRpcDocuments(Arc<RwLock<HashMap<String, Arc<Value>>>>);
+impl OpenRpcDocuments {
+    async fn set(&self, worker_id: &str, document: Value) {
+        self.0
+            .write()
+            .await
+            .insert(worker_id.to_string(), Arc::new(document));
+    }
+
+    async fn remove(&self, worker_id: &str) {
+        self.0.write().await.remove(worker_id);
+    }
+
+    pub async fn get(&self, worker_id: &str) -> Option<Arc<Value>> {
+        self.0.read().await.get(worker_id).cloned()
+    }
+}
+
+/// State kept about each worker next to the runtime.
+#[derive(Clone)]
+pub struct Workers {
+    pub loaded: LoadedWorkers,
+    pub failed: FailedWorkers,
+    pub signing: WorkerSigning,
+    pub limits: WorkerLimits,
+    pub openrpc: OpenRpcDocuments,
+    pub capture: RequestCapture,
+}
+impl Workers {
+    /// Record that the worker failed to load, it is no longer served.
+    async fn fail(&self, worker_id: &str, reason: &str) {
+        self.loaded.remove(worker_id).await;
+        self.failed.add(worker_id, reason).await;
+    }
+
+    async fn remove(&self, worker_id: &str) {
+        self.loaded.remove(worker_id).await;
+        self.failed.remove(worker_id).await;
+        self.signing.remove(worker_id).await;
+        self.limits.remove(worker_id).await;
+        self.openrpc.remove(worker_id).await;
+        self.capture.remove(worker_id).await;
+    }
 }
 
 async fn download_s3_object(s3_url: &str) -> miette::Result<Vec<u8>> {
@@ -74,6 +191,53 @@ fn is_s3_url(url: &str) -> bool {
     url.starts_with("s3://")
 }
 
+async fn fetch_openrpc(url: &str) -> miette::Result<Value> {
+    let bytes = if is_s3_url(url) {
+        download_s3_object(url).await?
+    } else {
+        reqwest::get(url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .into_diagnostic()
+            .context("Failed to get OpenRPC document")?
+            .bytes()
+            .await
+            .into_diagnostic()
+            .context("Failed to download OpenRPC document")?
+            .to_vec()
+    };
+
+    match serde_json::from_slice(&bytes) {
+        Ok(document @ Value::Object(_)) => Ok(document),
+        Ok(_) => miette::bail!("OpenRPC document must be an object"),
+        Err(err) => Err(err)
+            .into_diagnostic()
+            .context("Failed to parse OpenRPC document"),
+    }
+}
+
+/// Register a worker in the runtime, downloading it from S3 for `s3://` urls.
+pub async fn load_worker(
+    runtime: &Runtime,
+    worker_id: &str,
+    url: &str,
+    config: Value,
+) -> miette::Result<()> {
+    if is_s3_url(url) {
+        let bytes = download_s3_object(url).await?;
+        runtime
+            .register_worker(worker_id, &bytes, config)
+            .await
+            .into_diagnostic()
+    } else {
+        let url = Url::parse(url).into_diagnostic()?;
+        runtime
+            .register_worker_from_url(worker_id, &url, config)
+            .await
+            .into_diagnostic()
+    }
+}
+
 async fn try_patch_status(client: &Client, crd: &BaliusWorker, error: Option<String>) {
     if let Err(err) = patch_resource_status(
         client.clone(),
@@ -91,63 +255,153 @@ async fn try_patch_status(client: &Client, crd: &BaliusWorker, error: Option<Str
     };
 }
 
+/// Mirror the worker's signing keys into its status.
+pub async fn try_patch_keys(client: &Client, worker_id: &str, signer: &Mutex<Signer>) {
+    let Some((namespace, name)) = parse_worker_id(worker_id) else {
+        error!(worker = worker_id, "Invalid worker id");
+        return;
+    };
+
+    let keys = match signer.lock().await.list_keys(worker_id).await {
+        Ok(keys) => keys,
+        Err(err) => {
+            error!(worker = worker_id, err =? err, "Failed to list keys");
+            return;
+        }
+    };
+
+    if let Err(err) = patch_resource_status(
+        client.clone(),
+        namespace,
+        BaliusWorker::api_resource(),
+        name,
+        serde_json::json!({ "keys": keys }),
+    )
+    .await
+    {
+        error!(worker = worker_id, err =? err, "Failed to update keys in status");
+    };
+}
+
 async fn register_worker(
     client: Client,
     runtime: Runtime,
-    failed: FailedWorkers,
+    workers: &Workers,
+    signer: Arc<Mutex<Signer>>,
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
     crd: &BaliusWorker,
 ) {
-    let name = crd.name_any();
-    if is_s3_url(&crd.spec.url) {
-        match download_s3_object(&crd.spec.url).await {
-            Ok(bytes) => {
-                if let Err(err) = runtime
-                    .register_worker(&name, &bytes, Value::Object(crd.spec.config.clone()))
-                    .await
-                {
-                    failed.add(&name, &err.to_string()).await;
-                    try_patch_status(&client, crd, Some(err.to_string())).await;
-                    error!(err =? err, worker = name, "Error registering worker");
-                } else {
-                    failed.remove(&name).await;
-                    try_patch_status(&client, crd, None).await;
-                }
-            }
+    let name = crd.worker_id();
+    if let Err(err) = cleanup::check_name_available(pool, crd).await {
+        error!(err = err.to_string(), "Failed to register worker: {name}");
+        try_patch_status(&client, crd, Some(err.to_string())).await;
+        workers.fail(&name, &err.to_string()).await;
+        return;
+    }
+    if let Err(err) = cleanup::add_finalizer(&client, crd).await {
+        // The worker is still archived on the delete event while this instance is watching.
+        error!(
+            err = err.to_string(),
+            worker = name,
+            "Failed to add finalizer"
+        );
+    }
+
+    workers.limits.set(&name, &crd.spec.throughput_tier).await;
+    workers.signing.set(&name, &crd.spec).await;
+    workers
+        .capture
+        .set(&name, crd.spec.capture_requests.unwrap_or(false))
+        .await;
+    match crd.spec.openrpc_url.as_deref() {
+        Some(url) => match fetch_openrpc(url).await {
+            Ok(document) => workers.openrpc.set(&name, document).await,
             Err(err) => {
-                error!(err = err.to_string(), "Failed to register worker: {name}");
-                try_patch_status(&client, crd, Some(err.to_string())).await;
-                failed.add(&name, &err.to_string()).await;
+                // The worker is still served, `rpc.discover` falls back to it.
+                error!(
+                    err = err.to_string(),
+                    worker = name,
+                    "Failed to fetch OpenRPC document"
+                );
+                workers.openrpc.remove(&name).await;
             }
-        }
-    } else {
-        match Url::parse(&crd.spec.url) {
-            Ok(url) => {
-                if let Err(err) = runtime
-                    .register_worker_from_url(&name, &url, Value::Object(crd.spec.config.clone()))
-                    .await
-                {
-                    failed.add(&name, &err.to_string()).await;
-                    try_patch_status(&client, crd, Some(err.to_string())).await;
-                    error!(err =? err, worker = name, "Error registering worker");
-                } else {
-                    failed.remove(&name).await;
-                    try_patch_status(&client, crd, None).await;
+        },
+        None => workers.openrpc.remove(&name).await,
+    }
+    // Errors left by an earlier registration don't concern this one.
+    signer.lock().await.take_key_error(&name);
+    let result = match load_worker(
+        &runtime,
+        &name,
+        &crd.spec.url,
+        Value::Object(crd.spec.config.clone()),
+    )
+    .await
+    {
+        // The worker was handed an empty public key, it can't be served.
+        Ok(()) => match signer.lock().await.take_key_error(&name) {
+            Some(err) => {
+                if let Err(err) = runtime.remove_worker(&name).await {
+                    error!(err =? err, worker = name, "Failed to remove worker from runtime");
                 }
+                Err(miette::miette!(err))
             }
-            Err(err) => {
-                error!(err = err.to_string(), "Failed to register worker: {name}");
-                try_patch_status(&client, crd, Some(err.to_string())).await;
-                failed.add(&name, &err.to_string()).await;
-            }
-        }
+            None => Ok(()),
+        },
+        Err(err) => Err(err),
     };
+    match result {
+        Ok(()) => {
+            workers.loaded.add(&name, crd).await;
+            workers.failed.remove(&name).await;
+            try_patch_status(&client, crd, None).await;
+            try_patch_keys(&client, &name, &signer).await;
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "Failed to register worker: {name}");
+            try_patch_status(&client, crd, Some(err.to_string())).await;
+            workers.fail(&name, &err.to_string()).await;
+        }
+    }
+}
+
+/// Remove a worker being deleted from the runtime and archive it, which lets the deletion go
+/// through. The finalizer is kept when archiving fails, the next event for the worker retries.
+async fn finalize_worker(
+    client: &Client,
+    runtime: &Runtime,
+    workers: &Workers,
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    config: &Config,
+    crd: &BaliusWorker,
+) -> miette::Result<()> {
+    let name = crd.worker_id();
+    info!("Finalizing worker: {}", &name);
+    runtime
+        .remove_worker(&name)
+        .await
+        .into_diagnostic()
+        .context("removing worker from runtime")?;
+    workers.remove(&name).await;
+
+    if let Err(err) = cleanup::finalize_worker(client, pool, &config.shard, crd).await {
+        error!(
+            err = err.to_string(),
+            worker = name,
+            "Failed to archive deleted worker"
+        );
+    }
+    Ok(())
 }
 
 #[instrument("crdwatcher", skip_all)]
 pub async fn update_runtime(
     config: &Config,
     runtime: Runtime,
-    failed: FailedWorkers,
+    workers: Workers,
+    signer: Arc<Mutex<Signer>>,
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    workers_loaded: Flag,
 ) -> miette::Result<()> {
     let client = Client::try_default()
         .await
@@ -165,36 +419,56 @@ pub async fn update_runtime(
             }
 
             Ok(Some(Event::InitApply(crd))) => {
-                let name = crd.name_any();
+                let name = crd.worker_id();
+                if crd.metadata.deletion_timestamp.is_some() {
+                    if handle_legacy_networks(&crd.spec.network) == config.network {
+                        finalize_worker(&client, &runtime, &workers, &pool, config, &crd).await?;
+                    }
+                    continue;
+                }
                 if crd.spec.active.unwrap_or(true) {
                     if handle_legacy_networks(&crd.spec.network) == config.network {
                         info!("Registering worker: {}", &name);
-                        register_worker(client.clone(), runtime.clone(), failed.clone(), &crd)
-                            .await;
+                        register_worker(
+                            client.clone(),
+                            runtime.clone(),
+                            &workers,
+                            signer.clone(),
+                            &pool,
+                            &crd,
+                        )
+                        .await;
                     } else {
                         info!("New CRD doesn't match network: {}", &name);
                     }
                 } else {
                     info!(
-                        worker = &crd.name_any(),
+                        worker = &crd.worker_id(),
                         "worker is inactive, removing from runtime."
                     );
                     runtime
-                        .remove_worker(&crd.name_any())
+                        .remove_worker(&crd.worker_id())
                         .await
                         .into_diagnostic()
                         .context("removing worker from runtime")?;
-                    failed.remove(&crd.name_any()).await;
+                    workers.remove(&crd.worker_id()).await;
                     try_patch_status(&client, &crd, None).await;
                 }
             }
 
             Ok(Some(Event::InitDone)) => {
                 info!("Workers registered.");
+                workers_loaded.set(true);
             }
 
             Ok(Some(Event::Apply(crd))) => {
-                let name = crd.name_any();
+                let name = crd.worker_id();
+                if crd.metadata.deletion_timestamp.is_some() {
+                    if handle_legacy_networks(&crd.spec.network) == config.network {
+                        finalize_worker(&client, &runtime, &workers, &pool, config, &crd).await?;
+                    }
+                    continue;
+                }
                 if crd.spec.active.unwrap_or(true) {
                     if handle_legacy_networks(&crd.spec.network) == config.network {
                         if let Some(status) = crd.status.as_ref() {
@@ -203,7 +477,9 @@ pub async fn update_runtime(
                                 register_worker(
                                     client.clone(),
                                     runtime.clone(),
-                                    failed.clone(),
+                                    &workers,
+                                    signer.clone(),
+                                    &pool,
                                     &crd,
                                 )
                                 .await;
@@ -219,27 +495,37 @@ pub async fn update_runtime(
                     }
                 } else {
                     info!(
-                        worker = &crd.name_any(),
+                        worker = &crd.worker_id(),
                         "worker is inactive, removing from runtime."
                     );
                     runtime
-                        .remove_worker(&crd.name_any())
+                        .remove_worker(&crd.worker_id())
                         .await
                         .into_diagnostic()
                         .context("removing worker from runtime")?;
-                    failed.remove(&crd.name_any()).await;
+                    workers.remove(&crd.worker_id()).await;
                     try_patch_status(&client, &crd, None).await;
                 }
             }
 
             Ok(Some(Event::Delete(crd))) => {
-                info!("Removing worker: {}", crd.name_any());
+                info!("Removing worker: {}", crd.worker_id());
                 runtime
-                    .remove_worker(&crd.name_any())
+                    .remove_worker(&crd.worker_id())
                     .await
                     .into_diagnostic()
                     .context("removing worker from runtime")?;
-                failed.remove(&crd.name_any()).await;
+                workers.remove(&crd.worker_id()).await;
+
+                if handle_legacy_networks(&crd.spec.network) == config.network {
+                    if let Err(err) = cleanup::archive_worker(&pool, &config.shard, &crd).await {
+                        error!(
+                            err = err.to_string(),
+                            worker = crd.worker_id(),
+                            "Failed to archive deleted worker"
+                        );
+                    }
+                }
             }
 
             Ok(None) => {
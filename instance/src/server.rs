@@ -1,103 +1,965 @@
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram},
+    propagation::Extractor,
+    KeyValue,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::net::SocketAddr;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast::error::RecvError, Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
-use warp::Filter as _;
+use tracing::{debug, error, info_span, Instrument as _};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+use warp::{
+    http::{HeaderMap, StatusCode},
+    hyper::body::Bytes,
+    path::Tail,
+    reply::Reply as _,
+    sse, Filter as _,
+};
 
-use balius_runtime::{wit, Error, Runtime};
+use aws_lc_rs::constant_time;
+use balius_runtime::{wit, wit::balius::app::sign::SignError, Error, Runtime};
+use operator::worker_id;
 
-use crate::runtime::FailedWorkers;
+use crate::{
+    config::{Config, TierLimits},
+    events::BlockEvents,
+    logging::REQUEST_ID,
+    runtime::{LoadedWorkers, Workers},
+    signer::Signer,
+};
+
+const JSONRPC_VERSION: &str = "2.0";
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+/// Header carrying the secret the proxy authenticates itself with.
+const PROXY_SECRET_HEADER: &str = "x-balius-proxy-secret";
+/// Header carrying the id of the worker the proxy authenticated the request for.
+const CONSUMER_HEADER: &str = "x-balius-consumer";
+/// OpenRPC service discovery method.
+const DISCOVER_METHOD: &str = "rpc.discover";
+
+pub const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+/// Largest batch accepted. Batches run one call at a time, so this also bounds how long a batch
+/// can hold its in-flight slot.
+const MAX_BATCH_SIZE: usize = 50;
+
+/// Limits applied to the requests of a worker.
+#[derive(Clone)]
+struct WorkerLimit {
+    tier: Option<String>,
+    in_flight: Arc<Semaphore>,
+    timeout: Duration,
+}
+impl WorkerLimit {
+    /// Take one of the in-flight slots, held until the permit is dropped.
+    fn try_acquire(&self) -> Result<OwnedSemaphorePermit, ServerError> {
+        self.in_flight
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ServerError::Overloaded)
+    }
+
+    /// Wait for `future` up to the timeout.
+    async fn within_timeout<T>(&self, future: impl Future<Output = T>) -> Result<T, ServerError> {
+        tokio::time::timeout(self.timeout, future)
+            .await
+            .map_err(|_| ServerError::Timeout(self.timeout))
+    }
+}
+
+/// Per-worker request limits, sized by the worker's throughput tier. Tiers without configured
+/// limits get the default ones. Requests for unknown workers share a single default limit.
+#[derive(Clone)]
+pub struct WorkerLimits {
+    tiers: HashMap<String, TierLimits>,
+    default_timeout: Duration,
+    default_max_in_flight: usize,
+    unknown: WorkerLimit,
+    workers: Arc<RwLock<HashMap<String, WorkerLimit>>>,
+}
+impl WorkerLimits {
+    pub fn new(config: &Config) -> Self {
+        let default_max_in_flight = config
+            .max_in_flight_requests
+            .unwrap_or(DEFAULT_MAX_IN_FLIGHT);
+        let default_timeout = Duration::from_secs(
+            config
+                .request_timeout_seconds
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+        );
+
+        Self {
+            tiers: config.tiers.clone().unwrap_or_default(),
+            default_timeout,
+            default_max_in_flight,
+            unknown: WorkerLimit {
+                tier: None,
+                in_flight: Arc::new(Semaphore::new(default_max_in_flight)),
+                timeout: default_timeout,
+            },
+            workers: Default::default(),
+        }
+    }
+
+    fn for_tier(&self, tier: &str) -> WorkerLimit {
+        let limits = self.tiers.get(tier);
+        WorkerLimit {
+            tier: Some(tier.to_string()),
+            in_flight: Arc::new(Semaphore::new(
+                limits.map_or(self.default_max_in_flight, |x| x.max_in_flight),
+            )),
+            timeout: limits
+                .and_then(|x| x.timeout_seconds)
+                .map_or(self.default_timeout, Duration::from_secs),
+        }
+    }
+
+    /// Set the limits of a worker. Limits are kept as they are, along with the requests in
+    /// flight, unless the worker's tier changed.
+    pub async fn set(&self, worker_id: &str, tier: &str) {
+        let mut workers = self.workers.write().await;
+        if workers
+            .get(worker_id)
+            .is_some_and(|x| x.tier.as_deref() == Some(tier))
+        {
+            return;
+        }
+        workers.insert(worker_id.to_string(), self.for_tier(tier));
+    }
+
+    pub async fn remove(&self, worker_id: &str) {
+        self.workers.write().await.remove(worker_id);
+    }
+
+    async fn get(&self, worker_id: &str) -> WorkerLimit {
+        self.workers
+            .read()
+            .await
+            .get(worker_id)
+            .cloned()
+            .unwrap_or_else(|| self.unknown.clone())
+    }
+}
+
+struct ServerMetrics {
+    requests: Counter<u64>,
+    request_duration: Histogram<f64>,
+    rejected_requests: Counter<u64>,
+}
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        let meter = global::meter("baliusd");
+        Self {
+            requests: meter
+                .u64_counter("worker_requests")
+                .with_description("Worker requests handled, by method and outcome")
+                .build(),
+            request_duration: meter
+                .f64_histogram("worker_request_duration_seconds")
+                .with_description("Time taken to handle worker requests, by method and outcome")
+                .with_unit("s")
+                .build(),
+            rejected_requests: meter
+                .u64_counter("worker_requests_rejected")
+                .with_description(
+                    "Worker requests rejected for going over the in-flight limit or the timeout",
+                )
+                .build(),
+        }
+    }
+}
+impl ServerMetrics {
+    fn request(&self, worker: &str, method: &str, outcome: &'static str, duration: Duration) {
+        let labels = [
+            KeyValue::new("worker", worker.to_string()),
+            KeyValue::new("method", method.to_string()),
+            KeyValue::new("outcome", outcome),
+        ];
+        self.requests.add(1, &labels);
+        self.request_duration
+            .record(duration.as_secs_f64(), &labels);
+    }
+
+    fn rejected(&self, worker: &str, reason: &'static str) {
+        self.rejected_requests.add(
+            1,
+            &[
+                KeyValue::new("worker", worker.to_string()),
+                KeyValue::new("reason", reason),
+            ],
+        );
+    }
+}
+
+/// State shared by the RPC handlers.
+#[derive(Clone)]
+pub struct State {
+    runtime: Runtime,
+    workers: Workers,
+    metrics: Arc<ServerMetrics>,
+}
 
 #[derive(Deserialize)]
 struct Request {
-    pub id: Option<String>,
+    /// Clients predating JSON-RPC 2.0 support don't send it, so it's only checked when present.
+    pub jsonrpc: Option<String>,
     pub method: String,
-    pub params: serde_json::Value,
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// Errors answered by the server, each with its JSON-RPC error code and HTTP status.
+#[derive(Debug, thiserror::Error)]
+enum ServerError {
+    #[error("parse error: {0}")]
+    Parse(String),
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid params: {0}")]
+    InvalidParams(String),
+    #[error("method not found: {0}")]
+    MethodNotFound(String),
+    #[error("not found")]
+    NotFound,
+    #[error("unauthenticated")]
+    Unauthenticated,
+    #[error("worker not found: {0}")]
+    WorkerNotFound(String),
+    #[error("key not found: {0}")]
+    KeyNotFound(String),
+    #[error("failed to load into runtime: {0}")]
+    WorkerNotLoaded(String),
+    #[error("too many requests in flight for the worker")]
+    Overloaded,
+    #[error("request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("invalid worker response: {0}")]
+    InvalidResponse(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+impl ServerError {
+    fn code(&self) -> i64 {
+        match self {
+            Self::Parse(_) => -32700,
+            Self::InvalidRequest(_) => -32600,
+            Self::MethodNotFound(_) | Self::NotFound => -32601,
+            Self::InvalidParams(_) => -32602,
+            Self::InvalidResponse(_) | Self::Internal(_) | Self::KeyNotFound(_) => -32603,
+            Self::WorkerNotFound(_) => -32001,
+            Self::WorkerNotLoaded(_) | Self::Overloaded => -32000,
+            Self::Unauthenticated => -32003,
+            Self::Timeout(_) => -32002,
+        }
+    }
+
+    /// Outcome the request is reported with in metrics.
+    fn outcome(&self) -> &'static str {
+        match self {
+            Self::Parse(_)
+            | Self::InvalidRequest(_)
+            | Self::InvalidParams(_)
+            | Self::MethodNotFound(_)
+            | Self::NotFound
+            | Self::Unauthenticated
+            | Self::WorkerNotFound(_) => "bad_request",
+            Self::WorkerNotLoaded(_) => "failed_worker",
+            Self::KeyNotFound(_)
+            | Self::Overloaded
+            | Self::Timeout(_)
+            | Self::InvalidResponse(_)
+            | Self::Internal(_) => "worker_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Parse(_) | Self::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Self::MethodNotFound(_)
+            | Self::NotFound
+            | Self::WorkerNotFound(_)
+            | Self::KeyNotFound(_) => StatusCode::NOT_FOUND,
+            Self::InvalidParams(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::WorkerNotLoaded(_) | Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::InvalidResponse(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Plain `{error}` reply, for the endpoints that aren't JSON-RPC.
+    fn into_reply(self) -> warp::reply::Response {
+        warp::reply::with_status(
+            warp::reply::json(&json!({ "error": self.to_string() })),
+            self.status(),
+        )
+        .into_response()
+    }
+}
+impl From<Error> for ServerError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::WorkerNotFound(_) => Self::WorkerNotFound(err.to_string()),
+            Error::NoTarget => Self::MethodNotFound(err.to_string()),
+            err => Self::Internal(err.to_string()),
+        }
+    }
+}
+impl From<SignError> for ServerError {
+    fn from(err: SignError) -> Self {
+        match err {
+            SignError::KeyNotFound(x) => Self::KeyNotFound(x),
+            err => Self::Internal(format!("{err:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
 }
 
 #[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+    #[serde(skip)]
+    status: StatusCode,
+    /// Raw CBOR sent instead of the JSON-RPC response.
+    #[serde(skip)]
+    cbor: Option<Vec<u8>>,
 }
+impl RpcResponse {
+    fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION,
+            result: Some(result),
+            error: None,
+            id,
+            status: StatusCode::OK,
+            cbor: None,
+        }
+    }
 
-fn parse_request(body: serde_json::Value) -> Result<Request, ErrorResponse> {
-    match serde_json::from_value(body) {
-        Ok(x) => Ok(x),
-        Err(x) => Err(ErrorResponse {
-            error: x.to_string(),
-        }),
+    fn cbor(id: Value, cbor: Vec<u8>) -> Self {
+        Self {
+            cbor: Some(cbor),
+            ..Self::result(id, Value::Null)
+        }
+    }
+
+    fn from_result(id: Value, result: Result<CallResult, ServerError>) -> Self {
+        match result {
+            Ok(CallResult::Json(x)) => Self::result(id, x),
+            Ok(CallResult::Cbor(x)) => Self::cbor(id, x),
+            Err(err) => Self::error(id, err),
+        }
+    }
+
+    fn error(id: Value, error: ServerError) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION,
+            result: None,
+            status: error.status(),
+            error: Some(RpcError {
+                code: error.code(),
+                message: error.to_string(),
+            }),
+            id,
+            cbor: None,
+        }
+    }
+
+    fn into_reply(mut self) -> warp::reply::Response {
+        let status = self.status;
+        if let Some(cbor) = self.cbor.take() {
+            return warp::reply::with_status(
+                warp::reply::with_header(cbor, "content-type", CBOR_CONTENT_TYPE),
+                status,
+            )
+            .into_response();
+        }
+        warp::reply::with_status(warp::reply::json(&self), status).into_response()
     }
 }
 
-pub async fn handle_request(
-    state: (Runtime, FailedWorkers),
-    worker: String,
-    body: serde_json::Value,
-) -> warp::reply::Json {
-    let (runtime, failed) = state;
+/// Request id as recorded in the worker logs.
+fn request_id(id: &Value) -> Option<String> {
+    match id {
+        Value::Null => None,
+        Value::String(x) => Some(x.clone()),
+        x => Some(x.to_string()),
+    }
+}
 
-    if let Some(reason) = failed.read(&worker).await {
-        return warp::reply::json(&ErrorResponse {
-            error: format!("failed to load into runtime: {reason}"),
-        });
+fn into_json(response: wit::Response) -> Result<Value, ServerError> {
+    Ok(match response {
+        wit::Response::Acknowledge => json!({}),
+        wit::Response::Json(x) => serde_json::from_slice(&x)
+            .map_err(|err| ServerError::InvalidResponse(err.to_string()))?,
+        wit::Response::Cbor(x) => json!({ "cbor": hex::encode(x) }),
+        wit::Response::PartialTx(x) => json!({ "tx": hex::encode(x) }),
+    })
+}
+
+/// Result of a call as sent back to the client.
+enum CallResult {
+    Json(Value),
+    /// Raw CBOR, for clients that accept it.
+    Cbor(Vec<u8>),
+}
+impl CallResult {
+    /// CBOR and transaction responses are sent raw when `cbor` is set, and hex encoded inside
+    /// JSON otherwise.
+    fn encode(response: wit::Response, cbor: bool) -> Result<Self, ServerError> {
+        Ok(match response {
+            wit::Response::Cbor(x) | wit::Response::PartialTx(x) if cbor => Self::Cbor(x),
+            response => Self::Json(into_json(response)?),
+        })
     }
+}
 
-    let request = match parse_request(body) {
-        Ok(x) => x,
-        Err(err) => return warp::reply::json(&err),
-    };
+/// Whether a `Content-Type` or `Accept` header lists CBOR.
+fn is_cbor(header: Option<&str>) -> bool {
+    header.is_some_and(|header| {
+        header.split(',').any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|x| x.trim().eq_ignore_ascii_case(CBOR_CONTENT_TYPE))
+        })
+    })
+}
 
-    debug!(
-        worker,
-        id = request.id,
-        method = request.method,
-        "handling request"
-    );
+/// `in_batch` is set for the calls of a batch, which already holds an in-flight slot of the
+/// worker for all of them.
+async fn dispatch(
+    state: &State,
+    worker: &str,
+    method: &str,
+    params: Vec<u8>,
+    request_id: Option<String>,
+    in_batch: bool,
+) -> Result<wit::Response, ServerError> {
+    // Workers without an OpenRPC document in their spec can answer discovery themselves.
+    if method == DISCOVER_METHOD {
+        if let Some(document) = state.workers.openrpc.get(worker).await {
+            let document = serde_json::to_vec(&*document)
+                .map_err(|err| ServerError::Internal(err.to_string()))?;
+            return Ok(wit::Response::Json(document));
+        }
+    }
 
-    let params = serde_json::to_vec(&request.params).unwrap();
+    if let Some(reason) = state.workers.failed.read(worker).await {
+        return Err(ServerError::WorkerNotLoaded(reason));
+    }
+
+    let captured = state
+        .workers
+        .capture
+        .is_enabled(worker)
+        .await
+        .then(|| params.clone());
+    let start = Instant::now();
+    let result = call_worker(state, worker, method, params, request_id.clone(), in_batch).await;
+    if let Some(params) = captured {
+        state.workers.capture.record(
+            worker,
+            request_id,
+            method,
+            params,
+            result.as_ref().map_err(ToString::to_string),
+            start.elapsed(),
+        );
+    }
+    result
+}
 
-    let reply = runtime
-        .handle_request(&worker, &request.method, params)
-        .await;
+/// Run the request in the worker within its limits.
+///
+/// The timeout drops the request's future, which ends async work such as ledger or KV calls,
+/// but wasm that is running is not interrupted, as the runtime has no epoch or fuel limits
+/// configured. It bounds the response time, not the time the worker spends executing.
+async fn call_worker(
+    state: &State,
+    worker: &str,
+    method: &str,
+    params: Vec<u8>,
+    request_id: Option<String>,
+    in_batch: bool,
+) -> Result<wit::Response, ServerError> {
+    let limit = state.workers.limits.get(worker).await;
+    let _permit = if in_batch {
+        None
+    } else {
+        Some(acquire_in_flight(state, worker, &limit).await?)
+    };
+
+    debug!(worker, id = request_id, method, "handling request");
+
+    let reply = REQUEST_ID.scope(
+        request_id.clone(),
+        state
+            .runtime
+            .handle_request(worker, method, params)
+            .instrument(info_span!("runtime.handle_request", worker, method)),
+    );
+    let reply = match limit.within_timeout(reply).await {
+        Ok(reply) => reply,
+        Err(err) => {
+            record_rejected(state, worker, "timeout").await;
+            error!(worker, id = request_id, "request timed out");
+            return Err(err);
+        }
+    };
 
     match reply {
         Ok(x) => {
-            debug!(worker, id = request.id, "request successful");
+            debug!(worker, id = request_id, "request successful");
+            Ok(x)
+        }
+        Err(err) => {
+            error!(
+                worker,
+                id = request_id,
+                err = err.to_string(),
+                "request failed"
+            );
+            Err(err.into())
+        }
+    }
+}
+
+/// Take one of the worker's in-flight slots, recording the request as rejected when there are
+/// none left.
+async fn acquire_in_flight(
+    state: &State,
+    worker: &str,
+    limit: &WorkerLimit,
+) -> Result<OwnedSemaphorePermit, ServerError> {
+    let permit = limit.try_acquire();
+    if permit.is_err() {
+        record_rejected(state, worker, "in_flight").await;
+    }
+    permit
+}
+
+/// Check a JSON-RPC call and pass its params to the worker as JSON.
+async fn dispatch_json(
+    state: &State,
+    worker: &str,
+    request: Request,
+    request_id: Option<String>,
+    in_batch: bool,
+) -> Result<wit::Response, ServerError> {
+    if let Some(version) = request.jsonrpc.filter(|x| x != JSONRPC_VERSION) {
+        return Err(ServerError::InvalidRequest(format!(
+            "unsupported jsonrpc version: {version}"
+        )));
+    }
+    if !matches!(
+        request.params,
+        Value::Null | Value::Object(_) | Value::Array(_)
+    ) {
+        return Err(ServerError::InvalidParams(
+            "params must be an object or an array".into(),
+        ));
+    }
+
+    let params = serde_json::to_vec(&request.params)
+        .map_err(|err| ServerError::InvalidParams(err.to_string()))?;
+    dispatch(state, worker, &request.method, params, request_id, in_batch).await
+}
+
+/// Worker label for metrics. Worker ids come straight from the client, so the ones that aren't
+/// loaded are all labelled `unknown` to keep the number of series bounded.
+async fn worker_label<'a>(state: &State, worker: &'a str) -> &'a str {
+    if state.workers.loaded.contains(worker).await {
+        worker
+    } else {
+        "unknown"
+    }
+}
+
+/// Record a request rejected for going over one of the worker's limits.
+async fn record_rejected(state: &State, worker: &str, reason: &'static str) {
+    let worker = worker_label(state, worker).await;
+    state.metrics.rejected(worker, reason);
+}
 
-            let x = match x {
-                wit::Response::Acknowledge => json!({}),
-                wit::Response::Json(x) => serde_json::from_slice(&x).unwrap(),
-                wit::Response::Cbor(x) => json!({ "cbor": hex::encode(x) }),
-                wit::Response::PartialTx(x) => json!({ "tx": hex::encode(x) }),
+/// Record the request in metrics. Workers and methods come straight from the client, so the
+/// ones that don't exist are all labelled `unknown` to keep the number of series bounded.
+async fn record_request(
+    state: &State,
+    worker: &str,
+    method: Option<&str>,
+    error: Option<&ServerError>,
+    duration: Duration,
+) {
+    let outcome = error.map_or("ok", ServerError::outcome);
+    let worker = worker_label(state, worker).await;
+    let method = match (method, error) {
+        (None, _) => "invalid",
+        (Some(_), Some(ServerError::MethodNotFound(_))) => "unknown",
+        (Some(method), _) => method,
+    };
+    state.metrics.request(worker, method, outcome, duration);
+}
+
+/// Handle a single call. Returns `None` for notifications, which get no response. CBOR results
+/// are sent raw when `cbor` is set.
+async fn handle_call(
+    state: &State,
+    worker: &str,
+    call: Value,
+    cbor: bool,
+    in_batch: bool,
+) -> Option<RpcResponse> {
+    let id = call_id(&call);
+    let start = Instant::now();
+    let (method, result) = match serde_json::from_value::<Request>(call) {
+        Ok(request) => {
+            let request_id = id.as_ref().and_then(request_id);
+            let method = request.method.clone();
+            let result = dispatch_json(state, worker, request, request_id, in_batch)
+                .await
+                .and_then(|x| CallResult::encode(x, cbor));
+            (Some(method), result)
+        }
+        Err(err) => (None, Err(ServerError::InvalidRequest(err.to_string()))),
+    };
+    record_request(
+        state,
+        worker,
+        method.as_deref(),
+        result.as_ref().err(),
+        start.elapsed(),
+    )
+    .await;
+
+    let id = response_id(id, result.as_ref().err())?;
+    Some(RpcResponse::from_result(id, result))
+}
+
+/// Id of a call, `None` for notifications. Calls that aren't even objects get an error with a
+/// null id, as the spec requires.
+fn call_id(call: &Value) -> Option<Value> {
+    match call {
+        Value::Object(x) => x.get("id").cloned(),
+        _ => Some(Value::Null),
+    }
+}
+
+/// Id the call is answered with, `None` when it gets no response. Invalid requests are answered
+/// even without an id, as it can't be told whether they were meant as notifications.
+fn response_id(id: Option<Value>, error: Option<&ServerError>) -> Option<Value> {
+    match (id, error) {
+        (Some(id), _) => Some(id),
+        (None, Some(ServerError::InvalidRequest(_))) => Some(Value::Null),
+        (None, _) => None,
+    }
+}
+
+/// Reply to a batch, 204 when every call was a notification.
+fn batch_reply(responses: Vec<RpcResponse>) -> warp::reply::Response {
+    if responses.is_empty() {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        warp::reply::json(&responses).into_response()
+    }
+}
+
+/// Handle a call with CBOR params, passed to the worker as they are. There is no JSON-RPC
+/// envelope, the method is the rest of the path.
+async fn handle_cbor_call(
+    state: &State,
+    worker: &str,
+    method: &str,
+    params: Bytes,
+    cbor: bool,
+) -> RpcResponse {
+    let start = Instant::now();
+    let result = if method.is_empty() {
+        Err(ServerError::InvalidRequest(
+            "the method must be in the path for CBOR requests".into(),
+        ))
+    } else {
+        dispatch(state, worker, method, params.to_vec(), None, false)
+            .await
+            .and_then(|x| CallResult::encode(x, cbor))
+    };
+    record_request(
+        state,
+        worker,
+        Some(method).filter(|x| !x.is_empty()),
+        result.as_ref().err(),
+        start.elapsed(),
+    )
+    .await;
+
+    RpcResponse::from_result(Value::Null, result)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Span of a request, continuing the trace in its `traceparent` header if any.
+fn request_span(worker: &str, headers: &HeaderMap) -> tracing::Span {
+    let span = info_span!("request", worker);
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+    span
+}
+
+/// Parse a JSON-RPC body, rejecting batches that are empty or over [`MAX_BATCH_SIZE`].
+fn parse_body(body: &[u8]) -> Result<Value, ServerError> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(calls)) if calls.is_empty() => {
+            Err(ServerError::InvalidRequest("empty batch".into()))
+        }
+        Ok(Value::Array(calls)) if calls.len() > MAX_BATCH_SIZE => Err(
+            ServerError::InvalidRequest(format!("batches are limited to {MAX_BATCH_SIZE} calls")),
+        ),
+        Ok(x) => Ok(x),
+        Err(err) => Err(ServerError::Parse(err.to_string())),
+    }
+}
+
+/// Handle a JSON-RPC 2.0 request, either a single call or a batch of them. Single calls are
+/// answered with the HTTP status of their error, batches with 200 as they may mix outcomes.
+///
+/// Requests with a CBOR `Content-Type` carry the worker's params as they are, see
+/// [`handle_cbor_call`]. Single calls accepting CBOR get CBOR results raw instead of hex encoded.
+pub async fn handle_request(
+    state: State,
+    worker: String,
+    method: Tail,
+    content_type: Option<String>,
+    accept: Option<String>,
+    body: Bytes,
+) -> warp::reply::Response {
+    let cbor = is_cbor(accept.as_deref());
+    if is_cbor(content_type.as_deref()) {
+        return handle_cbor_call(&state, &worker, method.as_str(), body, cbor)
+            .await
+            .into_reply();
+    }
+
+    let start = Instant::now();
+    let body = match parse_body(&body) {
+        Ok(x) => x,
+        Err(err) => {
+            record_request(&state, &worker, None, Some(&err), start.elapsed()).await;
+            return RpcResponse::error(Value::Null, err).into_reply();
+        }
+    };
+
+    match body {
+        Value::Array(calls) => {
+            // The whole batch takes a single in-flight slot and runs one call at a time, so it
+            // can't take more than its share of the worker.
+            let limit = state.workers.limits.get(&worker).await;
+            let _permit = match acquire_in_flight(&state, &worker, &limit).await {
+                Ok(permit) => permit,
+                Err(err) => {
+                    record_request(&state, &worker, None, Some(&err), start.elapsed()).await;
+                    return RpcResponse::error(Value::Null, err).into_reply();
+                }
             };
 
-            warp::reply::json(&x)
+            let mut responses = Vec::with_capacity(calls.len());
+            for call in calls {
+                responses.extend(handle_call(&state, &worker, call, false, true).await);
+            }
+
+            batch_reply(responses)
         }
+        call => match handle_call(&state, &worker, call, cbor, false).await {
+            Some(response) => response.into_reply(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        },
+    }
+}
+
+/// List the worker's signing keys along with their latest public keys.
+pub async fn handle_keys(signer: Arc<Mutex<Signer>>, worker: String) -> warp::reply::Response {
+    match signer.lock().await.list_keys(&worker).await {
+        Ok(keys) => warp::reply::json(&json!({ "keys": keys })).into_response(),
         Err(err) => {
-            error!(worker, id = request.id, "request failed");
-            warp::reply::json(&ErrorResponse {
-                error: err.to_string(),
-            })
+            error!(worker, err =? err, "failed to list keys");
+            ServerError::from(err).into_reply()
+        }
+    }
+}
+
+/// Stream the blocks applied and undone for the worker as server-sent events. Events are those of
+/// the instance's shard, forwarded while the worker is loaded. Subscribers that fall behind get a
+/// `lagged` event with the number of events they missed.
+pub async fn handle_events(
+    events: BlockEvents,
+    loaded: LoadedWorkers,
+    worker: String,
+) -> warp::reply::Response {
+    let stream = futures_util::stream::unfold(events.subscribe(), move |mut receiver| {
+        let loaded = loaded.clone();
+        let worker = worker.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(block) if loaded.contains(&worker).await => {
+                        let event = sse::Event::default().event(block.name()).json_data(&*block);
+                        return Some((event, receiver));
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        let event = sse::Event::default()
+                            .event("lagged")
+                            .data(skipped.to_string());
+                        return Some((Ok(event), receiver));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
         }
+    });
+
+    sse::reply(sse::keep_alive().stream(stream)).into_response()
+}
+
+#[derive(Debug)]
+struct Unauthenticated;
+impl warp::reject::Reject for Unauthenticated {}
+
+/// Check that the request comes from the proxy and was authenticated for `worker`. Everything is
+/// accepted when no secret is configured.
+fn authenticate(
+    secret: Option<&str>,
+    worker: String,
+    proxy_secret: Option<String>,
+    consumer: Option<String>,
+) -> Result<String, warp::Rejection> {
+    let Some(secret) = secret else {
+        return Ok(worker);
+    };
+    let authenticated = proxy_secret.is_some_and(|x| {
+        constant_time::verify_slices_are_equal(x.as_bytes(), secret.as_bytes()).is_ok()
+    });
+    if authenticated && consumer.as_deref() == Some(worker.as_str()) {
+        Ok(worker)
+    } else {
+        Err(warp::reject::custom(Unauthenticated))
+    }
+}
+
+async fn handle_rejection(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if rejection.find::<Unauthenticated>().is_some() {
+        Ok(ServerError::Unauthenticated.into_reply())
+    } else if rejection.is_not_found() {
+        Ok(ServerError::NotFound.into_reply())
+    } else {
+        Err(rejection)
     }
 }
 
 pub async fn serve(
     config: balius_runtime::drivers::jsonrpc::Config,
     runtime: Runtime,
-    failed: FailedWorkers,
+    workers: Workers,
+    signer: Arc<Mutex<Signer>>,
+    events: BlockEvents,
+    proxy_secret: Option<String>,
     cancel: CancellationToken,
 ) -> Result<(), Error> {
-    let filter = warp::any()
-        .map(move || (runtime.clone(), failed.clone()))
+    let loaded = workers.loaded.clone();
+    let state = State {
+        runtime,
+        workers,
+        metrics: Default::default(),
+    };
+
+    if proxy_secret.is_none() {
+        tracing::warn!(
+            "allow_unauthenticated set, workers can be called without going through the proxy"
+        );
+    }
+    let proxy_secret = Arc::new(proxy_secret);
+
+    // Workers are addressed as `/{namespace}/{name}`.
+    let worker = warp::path::param()
         .and(warp::path::param())
+        .map(|namespace: String, name: String| worker_id(&namespace, &name))
+        .and(warp::header::optional::<String>(PROXY_SECRET_HEADER))
+        .and(warp::header::optional::<String>(CONSUMER_HEADER))
+        .and_then(move |worker, secret, consumer| {
+            let proxy_secret = proxy_secret.clone();
+            async move { authenticate(proxy_secret.as_deref(), worker, secret, consumer) }
+        });
+
+    let rpc = warp::any()
+        .map(move || state.clone())
+        .and(worker.clone())
         .and(warp::post())
-        .and(warp::body::json())
-        .then(handle_request)
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .then(
+            |state, worker: String, method, content_type, accept, headers: HeaderMap, body| {
+                let span = request_span(&worker, &headers);
+                handle_request(state, worker, method, content_type, accept, body).instrument(span)
+            },
+        );
+
+    let keys = warp::any()
+        .map(move || signer.clone())
+        .and(worker.clone())
+        .and(warp::path!("keys"))
+        .and(warp::get())
+        .then(handle_keys);
+
+    let events = warp::any()
+        .map(move || (events.clone(), loaded.clone()))
+        .untuple_one()
+        .and(worker)
+        .and(warp::path!("events"))
+        .and(warp::get())
+        .then(handle_events);
+
+    // CORS goes after the rejection handler so that error responses carry its headers too.
+    let filter = rpc
+        .or(keys)
+        .or(events)
+        .recover(handle_rejection)
         .with(
             warp::cors()
                 .allow_any_origin()
+                .allow_method("GET")
                 .allow_method("POST")
                 .allow_method("OPTIONS")
                 .allow_headers(vec!["content-type", "dmtr-api-key"])
@@ -119,3 +981,307 @@ pub async fn serve(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_batches() {
+        assert!(matches!(
+            parse_body(b"[]"),
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert!(matches!(parse_body(b"[{"), Err(ServerError::Parse(_))));
+
+        let call = json!({ "jsonrpc": "2.0", "method": "foo", "id": 1 });
+        let batch = serde_json::to_vec(&vec![call.clone(); MAX_BATCH_SIZE]).unwrap();
+        assert!(matches!(parse_body(&batch), Ok(Value::Array(x)) if x.len() == MAX_BATCH_SIZE));
+
+        let batch = serde_json::to_vec(&vec![call.clone(); MAX_BATCH_SIZE + 1]).unwrap();
+        let err = parse_body(&batch).unwrap_err();
+        assert_eq!(err.code(), -32600);
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let single = serde_json::to_vec(&call).unwrap();
+        assert_eq!(parse_body(&single).unwrap(), call);
+    }
+
+    async fn json_body(response: warp::reply::Response) -> Value {
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Response to a call that ended with `result`, as `handle_call` builds it.
+    fn answer(call: &Value, result: Result<CallResult, ServerError>) -> Option<RpcResponse> {
+        let id = response_id(call_id(call), result.as_ref().err())?;
+        Some(RpcResponse::from_result(id, result))
+    }
+
+    #[tokio::test]
+    async fn batches_over_the_limit_are_rejected() {
+        let call = json!({ "jsonrpc": "2.0", "method": "foo", "id": 1 });
+        let batch = serde_json::to_vec(&vec![call; MAX_BATCH_SIZE + 1]).unwrap();
+        let err = parse_body(&batch).unwrap_err();
+
+        let response = RpcResponse::error(Value::Null, err).into_reply();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["error"]["code"], -32600);
+        assert_eq!(body["id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn batch_notifications_get_no_response() {
+        let calls = [
+            (
+                json!({ "jsonrpc": "2.0", "method": "a", "id": 1 }),
+                Ok(CallResult::Json(json!("one"))),
+            ),
+            (
+                json!({ "jsonrpc": "2.0", "method": "b" }),
+                Ok(CallResult::Json(json!("two"))),
+            ),
+            (
+                json!({ "jsonrpc": "2.0", "method": "c" }),
+                Err(ServerError::Internal("failed".into())),
+            ),
+            (
+                json!({ "jsonrpc": "2.0", "method": "d", "id": "x" }),
+                Err(ServerError::MethodNotFound("d".into())),
+            ),
+            (
+                json!({ "jsonrpc": "2.0" }),
+                Err(ServerError::InvalidRequest("missing method".into())),
+            ),
+            (
+                json!(42),
+                Err(ServerError::InvalidRequest("not an object".into())),
+            ),
+        ];
+        let responses = calls
+            .into_iter()
+            .filter_map(|(call, result)| answer(&call, result))
+            .collect();
+
+        let response = batch_reply(responses);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        let ids: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["id"].clone())
+            .collect();
+        assert_eq!(ids, [json!(1), json!("x"), Value::Null, Value::Null]);
+        assert_eq!(body[0]["result"], "one");
+        assert_eq!(body[1]["error"]["code"], -32601);
+        assert_eq!(body[2]["error"]["code"], -32600);
+    }
+
+    #[test]
+    fn batches_of_notifications_get_no_content() {
+        let responses: Vec<_> = [
+            json!({ "jsonrpc": "2.0", "method": "a" }),
+            json!({ "jsonrpc": "2.0", "method": "b", "params": [] }),
+        ]
+        .iter()
+        .filter_map(|call| answer(call, Ok(CallResult::Json(json!({})))))
+        .collect();
+        assert!(responses.is_empty());
+        assert_eq!(batch_reply(responses).status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn error_codes_and_statuses() {
+        let cases = [
+            (
+                ServerError::Parse("".into()),
+                -32700,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ServerError::InvalidRequest("".into()),
+                -32600,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ServerError::MethodNotFound("".into()),
+                -32601,
+                StatusCode::NOT_FOUND,
+            ),
+            (ServerError::NotFound, -32601, StatusCode::NOT_FOUND),
+            (
+                ServerError::InvalidParams("".into()),
+                -32602,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ServerError::InvalidResponse("".into()),
+                -32603,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ServerError::Internal("".into()),
+                -32603,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ServerError::KeyNotFound("".into()),
+                -32603,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                ServerError::WorkerNotFound("".into()),
+                -32001,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                ServerError::WorkerNotLoaded("".into()),
+                -32000,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                ServerError::Overloaded,
+                -32000,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                ServerError::Timeout(Duration::from_secs(1)),
+                -32002,
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (
+                ServerError::Unauthenticated,
+                -32003,
+                StatusCode::UNAUTHORIZED,
+            ),
+        ];
+        for (err, code, status) in cases {
+            assert_eq!(err.code(), code, "{err}");
+            assert_eq!(err.status(), status, "{err}");
+        }
+    }
+
+    #[test]
+    fn cbor_negotiation() {
+        assert!(is_cbor(Some("application/cbor")));
+        assert!(is_cbor(Some("Application/CBOR; charset=binary")));
+        assert!(is_cbor(Some("application/json, application/cbor;q=0.9")));
+        assert!(!is_cbor(Some("application/json")));
+        assert!(!is_cbor(Some("application/cbor-seq")));
+        assert!(!is_cbor(Some("*/*")));
+        assert!(!is_cbor(None));
+    }
+
+    #[tokio::test]
+    async fn cbor_results_are_raw_when_accepted() {
+        for response in [
+            wit::Response::Cbor(vec![0x82, 0x01, 0x02]),
+            wit::Response::PartialTx(vec![0x82, 0x01, 0x02]),
+        ] {
+            let result = CallResult::encode(response, true).unwrap();
+            let response = RpcResponse::from_result(Value::Null, Ok(result)).into_reply();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["content-type"], CBOR_CONTENT_TYPE);
+            let body = warp::hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap();
+            assert_eq!(body.as_ref(), [0x82, 0x01, 0x02]);
+        }
+    }
+
+    #[tokio::test]
+    async fn cbor_results_fall_back_to_json() {
+        let result = CallResult::encode(wit::Response::Cbor(vec![0x82, 0x01]), false).unwrap();
+        let response = RpcResponse::from_result(json!(1), Ok(result)).into_reply();
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = json_body(response).await;
+        assert_eq!(body["result"], json!({ "cbor": "8201" }));
+
+        let result = CallResult::encode(wit::Response::PartialTx(vec![0xa0]), false).unwrap();
+        let body = json_body(RpcResponse::from_result(json!(1), Ok(result)).into_reply()).await;
+        assert_eq!(body["result"], json!({ "tx": "a0" }));
+
+        // JSON results stay JSON even when CBOR is accepted.
+        let result = CallResult::encode(wit::Response::Json(b"{\"a\":1}".to_vec()), true).unwrap();
+        let body = json_body(RpcResponse::from_result(json!(1), Ok(result)).into_reply()).await;
+        assert_eq!(body["result"], json!({ "a": 1 }));
+    }
+
+    fn limit(max_in_flight: usize, timeout: Duration) -> WorkerLimit {
+        WorkerLimit {
+            tier: None,
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            timeout,
+        }
+    }
+
+    #[test]
+    fn requests_over_the_in_flight_limit_are_rejected() {
+        let limit = limit(2, Duration::from_secs(1));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+
+        let err = limit.try_acquire().unwrap_err();
+        assert!(matches!(err, ServerError::Overloaded));
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.code(), -32000);
+        assert_eq!(err.outcome(), "worker_error");
+
+        // Slots are given back when the requests holding them are done.
+        drop(first);
+        assert!(limit.try_acquire().is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_timeout_fail() {
+        let limit = limit(1, Duration::from_millis(10));
+        assert_eq!(limit.within_timeout(async { 1 }).await.unwrap(), 1);
+
+        let err = limit
+            .within_timeout(std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServerError::Timeout(x) if x == Duration::from_millis(10)));
+        assert_eq!(err.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.code(), -32002);
+        assert_eq!(err.outcome(), "worker_error");
+    }
+
+    #[test]
+    fn proxy_authentication() {
+        let authenticated = |header: Option<&str>, consumer: Option<&str>| {
+            authenticate(
+                Some("s3cret"),
+                "ns.worker".into(),
+                header.map(Into::into),
+                consumer.map(Into::into),
+            )
+            .map_err(|x| assert!(x.find::<Unauthenticated>().is_some()))
+            .is_ok()
+        };
+
+        assert!(authenticated(Some("s3cret"), Some("ns.worker")));
+        // Missing secret.
+        assert!(!authenticated(None, Some("ns.worker")));
+        // Wrong secret, including one that only shares a prefix.
+        assert!(!authenticated(Some("wrong"), Some("ns.worker")));
+        assert!(!authenticated(Some("s3cre"), Some("ns.worker")));
+        // Authenticated for another worker, or for none.
+        assert!(!authenticated(Some("s3cret"), Some("ns.other")));
+        assert!(!authenticated(Some("s3cret"), None));
+
+        // Without a configured secret, which takes `allow_unauthenticated`, anything goes.
+        assert!(authenticate(None, "ns.worker".into(), None, None).is_ok());
+    }
+
+    #[test]
+    fn request_ids() {
+        assert_eq!(request_id(&Value::Null), None);
+        assert_eq!(request_id(&json!("abc")).as_deref(), Some("abc"));
+        assert_eq!(request_id(&json!(7)).as_deref(), Some("7"));
+    }
+}
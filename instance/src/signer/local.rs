@@ -0,0 +1,558 @@
+/// Software signer backend, meant for local development and CI.
+///
+///
+/// Keys are ed25519 keys kept in an encrypted keystore. Each key is encrypted with AES-256-GCM
+/// under a key derived from the configured passphrase (PBKDF2-HMAC-SHA256 with a random salt per
+/// key). The keystore is either a JSON file or, when no path is configured, a table named
+/// `keystore`, created by the migrations under `migrations/`:
+///
+/// ```sql
+/// CREATE TABLE keystore (
+///     name VARCHAR(600) NOT NULL, -- {worker}-{key}, same as the transit key name on vault
+///     version BIGINT NOT NULL,    -- starts at 1 and increases on every rotation
+///     salt BYTEA NOT NULL,
+///     nonce BYTEA NOT NULL,
+///     ciphertext BYTEA NOT NULL,  -- encrypted PKCS#8 document
+///     PRIMARY KEY (name, version)
+/// );
+/// ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+
+use aws_lc_rs::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    pbkdf2,
+    rand::{self, SystemRandom},
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use balius_runtime::wit::balius::app::sign as wit;
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::NoTls;
+
+use crate::config::KeystoreConfig;
+
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct EncryptedKey {
+    #[serde(with = "hex")]
+    salt: Vec<u8>,
+    #[serde(with = "hex")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex")]
+    ciphertext: Vec<u8>,
+}
+
+type KeystoreFile = HashMap<String, BTreeMap<u64, EncryptedKey>>;
+
+/// Versions of a key in a keystore file. Files written before keys had versions hold a single
+/// key per name, which is read as its version 1.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredKey {
+    Versions(BTreeMap<u64, EncryptedKey>),
+    Unversioned(EncryptedKey),
+}
+impl From<StoredKey> for BTreeMap<u64, EncryptedKey> {
+    fn from(value: StoredKey) -> Self {
+        match value {
+            StoredKey::Versions(versions) => versions,
+            StoredKey::Unversioned(key) => BTreeMap::from([(1, key)]),
+        }
+    }
+}
+
+enum Keystore {
+    File(PathBuf),
+    Postgres(Pool<PostgresConnectionManager<NoTls>>),
+}
+impl Keystore {
+    fn read_file(path: &Path) -> Result<KeystoreFile, wit::SignError> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str::<HashMap<String, StoredKey>>(&contents)
+                .map(|keys| {
+                    keys.into_iter()
+                        .map(|(name, key)| (name, key.into()))
+                        .collect()
+                })
+                .map_err(|err| wit::SignError::Internal(format!("invalid keystore file: {err}"))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(err) => Err(wit::SignError::Internal(format!(
+                "failed to read keystore file: {err}"
+            ))),
+        }
+    }
+
+    fn write_file(path: &Path, keys: &KeystoreFile) -> Result<(), wit::SignError> {
+        let contents = serde_json::to_string_pretty(keys)
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+
+        // Write to a temporary file first so a crash never leaves a truncated keystore.
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|err| {
+                wit::SignError::Internal(format!("failed to write keystore file: {err}"))
+            })
+    }
+
+    async fn latest_version(&self, name: &str) -> Result<Option<u64>, wit::SignError> {
+        match self {
+            Self::File(path) => Ok(Self::read_file(path)?
+                .get(name)
+                .and_then(|versions| versions.keys().next_back().copied())),
+            Self::Postgres(pool) => {
+                let conn = pool
+                    .get()
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                let row = conn
+                    .query_one(
+                        "SELECT MAX(version) FROM keystore WHERE name = $1::TEXT",
+                        &[&name],
+                    )
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                let version: Option<i64> = row.get(0);
+                Ok(version.map(|x| x as u64))
+            }
+        }
+    }
+
+    async fn get(&self, name: &str, version: u64) -> Result<Option<EncryptedKey>, wit::SignError> {
+        match self {
+            Self::File(path) => Ok(Self::read_file(path)?
+                .get_mut(name)
+                .and_then(|versions| versions.remove(&version))),
+            Self::Postgres(pool) => {
+                let conn = pool
+                    .get()
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                let row = conn
+                    .query_opt(
+                        "SELECT salt, nonce, ciphertext FROM keystore
+                         WHERE name = $1::TEXT AND version = $2::BIGINT",
+                        &[&name, &(version as i64)],
+                    )
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                Ok(row.map(|row| EncryptedKey {
+                    salt: row.get(0),
+                    nonce: row.get(1),
+                    ciphertext: row.get(2),
+                }))
+            }
+        }
+    }
+
+    /// Store a new version of a key. An existing version is never overwritten.
+    async fn insert(
+        &self,
+        name: &str,
+        version: u64,
+        key: &EncryptedKey,
+    ) -> Result<(), wit::SignError> {
+        match self {
+            Self::File(path) => {
+                let mut keys = Self::read_file(path)?;
+                keys.entry(name.to_string())
+                    .or_default()
+                    .entry(version)
+                    .or_insert_with(|| key.clone());
+                Self::write_file(path, &keys)
+            }
+            Self::Postgres(pool) => {
+                let conn = pool
+                    .get()
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                conn.execute(
+                    "INSERT INTO keystore (name, version, salt, nonce, ciphertext)
+                     VALUES ($1::TEXT, $2::BIGINT, $3::BYTEA, $4::BYTEA, $5::BYTEA)
+                     ON CONFLICT (name, version) DO NOTHING;",
+                    &[
+                        &name,
+                        &(version as i64),
+                        &key.salt,
+                        &key.nonce,
+                        &key.ciphertext,
+                    ],
+                )
+                .await
+                .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove every version of a key.
+    async fn remove(&self, name: &str) -> Result<(), wit::SignError> {
+        match self {
+            Self::File(path) => {
+                let mut keys = Self::read_file(path)?;
+                if keys.remove(name).is_none() {
+                    return Ok(());
+                }
+                Self::write_file(path, &keys)
+            }
+            Self::Postgres(pool) => {
+                let conn = pool
+                    .get()
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                conn.execute("DELETE FROM keystore WHERE name = $1::TEXT", &[&name])
+                    .await
+                    .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct LocalSigner {
+    keystore: Keystore,
+    passphrase: String,
+    rng: SystemRandom,
+    cache: HashMap<(String, u64), Ed25519KeyPair>,
+}
+impl LocalSigner {
+    pub fn try_new(
+        config: &KeystoreConfig,
+        pool: &Pool<PostgresConnectionManager<NoTls>>,
+    ) -> miette::Result<Self> {
+        if config.passphrase.is_empty() {
+            miette::bail!("keystore passphrase must not be empty");
+        }
+
+        let keystore = match &config.path {
+            Some(path) => Keystore::File(path.clone()),
+            None => Keystore::Postgres(pool.clone()),
+        };
+
+        Ok(Self {
+            keystore,
+            passphrase: config.passphrase.clone(),
+            rng: SystemRandom::new(),
+            cache: HashMap::new(),
+        })
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<LessSafeKey, wit::SignError> {
+        let mut secret = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt,
+            self.passphrase.as_bytes(),
+            &mut secret,
+        );
+        let key = UnboundKey::new(&AES_256_GCM, &secret)
+            .map_err(|_| wit::SignError::Internal("failed to derive keystore key".into()))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    /// Associated data binding a ciphertext to the key name and version it was stored under.
+    fn aad(name: &str, version: u64) -> String {
+        format!("{name}:{version}")
+    }
+
+    fn encrypt(
+        &self,
+        name: &str,
+        version: u64,
+        pkcs8: &[u8],
+    ) -> Result<EncryptedKey, wit::SignError> {
+        let mut salt = vec![0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::fill(&mut salt)
+            .and_then(|_| rand::fill(&mut nonce))
+            .map_err(|_| wit::SignError::Internal("failed to generate randomness".into()))?;
+
+        let mut ciphertext = pkcs8.to_vec();
+        self.cipher(&salt)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(Self::aad(name, version).as_bytes()),
+                &mut ciphertext,
+            )
+            .map_err(|_| wit::SignError::Internal("failed to encrypt key".into()))?;
+
+        Ok(EncryptedKey {
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    fn decrypt(
+        &self,
+        name: &str,
+        version: u64,
+        key: &EncryptedKey,
+    ) -> Result<Ed25519KeyPair, wit::SignError> {
+        if Nonce::try_assume_unique_for_key(&key.nonce).is_err() {
+            return Err(wit::SignError::Internal("invalid nonce in keystore".into()));
+        }
+
+        let cipher = self.cipher(&key.salt)?;
+        let open = |aad: &str| {
+            let nonce = Nonce::try_assume_unique_for_key(&key.nonce).ok()?;
+            let mut in_out = key.ciphertext.clone();
+            let len = cipher
+                .open_in_place(nonce, Aad::from(aad.as_bytes()), &mut in_out)
+                .ok()?
+                .len();
+            in_out.truncate(len);
+            Some(in_out)
+        };
+        // Keys stored before they had versions are bound to their name only.
+        let pkcs8 = open(&Self::aad(name, version))
+            .or_else(|| (version == 1).then(|| open(name)).flatten())
+            .ok_or_else(|| {
+                wit::SignError::Internal("failed to decrypt key, wrong passphrase?".into())
+            })?;
+
+        Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|err| wit::SignError::Internal(format!("invalid key in keystore: {err}")))
+    }
+
+    /// Load a version of the key pair named `name`, decrypting it from the keystore on first use.
+    async fn load(
+        &mut self,
+        name: &str,
+        version: u64,
+    ) -> Result<Option<&Ed25519KeyPair>, wit::SignError> {
+        let cache_key = (name.to_string(), version);
+        if !self.cache.contains_key(&cache_key) {
+            let Some(encrypted) = self.keystore.get(name, version).await? else {
+                return Ok(None);
+            };
+            let pair = self.decrypt(name, version, &encrypted)?;
+            self.cache.insert(cache_key.clone(), pair);
+        }
+
+        Ok(self.cache.get(&cache_key))
+    }
+
+    /// Generate and store `version` of the key named `name`, returning its public key.
+    async fn create_version(
+        &mut self,
+        name: &str,
+        version: u64,
+    ) -> Result<Vec<u8>, wit::SignError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&self.rng)
+            .map_err(|_| wit::SignError::Internal("failed to generate key".into()))?;
+        let encrypted = self.encrypt(name, version, pkcs8.as_ref())?;
+        self.keystore.insert(name, version, &encrypted).await?;
+
+        // Read back instead of using the generated key, in case another instance won the race.
+        match self.load(name, version).await? {
+            Some(pair) => Ok(pair.public_key().as_ref().to_vec()),
+            None => Err(wit::SignError::KeyNotFound(name.to_string())),
+        }
+    }
+
+    /// Latest version of the key along with its public key, or `None` if the key doesn't exist.
+    pub async fn latest_public_key(
+        &mut self,
+        name: &str,
+    ) -> Result<Option<(u64, Vec<u8>)>, wit::SignError> {
+        let Some(version) = self.keystore.latest_version(name).await? else {
+            return Ok(None);
+        };
+        Ok(self
+            .load(name, version)
+            .await?
+            .map(|pair| (version, pair.public_key().as_ref().to_vec())))
+    }
+
+    pub async fn try_add_key(
+        &mut self,
+        name: &str,
+        algorithm: &str,
+    ) -> Result<Vec<u8>, wit::SignError> {
+        if algorithm != "ed25519" {
+            return Err(wit::SignError::Internal(format!(
+                "unsupported algorithm: {algorithm}"
+            )));
+        }
+
+        match self.keystore.latest_version(name).await? {
+            Some(version) => match self.load(name, version).await? {
+                Some(pair) => Ok(pair.public_key().as_ref().to_vec()),
+                None => Err(wit::SignError::KeyNotFound(name.to_string())),
+            },
+            None => self.create_version(name, 1).await,
+        }
+    }
+
+    pub async fn rotate_key(&mut self, name: &str) -> Result<(u64, Vec<u8>), wit::SignError> {
+        let Some(latest) = self.keystore.latest_version(name).await? else {
+            return Err(wit::SignError::KeyNotFound(name.to_string()));
+        };
+
+        let public_key = self.create_version(name, latest + 1).await?;
+        Ok((latest + 1, public_key))
+    }
+
+    pub async fn sign(
+        &mut self,
+        name: &str,
+        payload: &[u8],
+        version: Option<u64>,
+    ) -> Result<(u64, wit::Signature), wit::SignError> {
+        let version = match version {
+            Some(version) => version,
+            None => match self.keystore.latest_version(name).await? {
+                Some(version) => version,
+                None => return Err(wit::SignError::KeyNotFound(name.to_string())),
+            },
+        };
+
+        match self.load(name, version).await? {
+            Some(pair) => Ok((version, pair.sign(payload).as_ref().to_vec())),
+            None => Err(wit::SignError::KeyNotFound(format!(
+                "{name} version {version}"
+            ))),
+        }
+    }
+
+    /// Remove every version of a key. Removing a key that doesn't exist is not an error.
+    pub async fn delete_key(&mut self, name: &str) -> Result<(), wit::SignError> {
+        self.keystore.remove(name).await?;
+        self.cache.retain(|(cached, _), _| *cached != name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(path: PathBuf) -> LocalSigner {
+        LocalSigner {
+            keystore: Keystore::File(path),
+            passphrase: "passphrase".into(),
+            rng: SystemRandom::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    fn keystore_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "baliusd-keystore-{test}-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn keys_are_bound_to_their_name_and_version() {
+        let signer = signer(keystore_path("bound"));
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&signer.rng).unwrap();
+        let encrypted = signer.encrypt("ns.worker-key", 2, pkcs8.as_ref()).unwrap();
+
+        assert!(signer.decrypt("ns.worker-key", 2, &encrypted).is_ok());
+        assert!(signer.decrypt("ns.worker-key", 1, &encrypted).is_err());
+        assert!(signer.decrypt("ns.other-key", 2, &encrypted).is_err());
+    }
+
+    #[test]
+    fn unversioned_keys_are_read_as_version_one() {
+        let signer = signer(keystore_path("unversioned"));
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&signer.rng).unwrap();
+
+        // Encrypted the way keys were before they had versions.
+        let salt = vec![1u8; SALT_LEN];
+        let nonce = [2u8; NONCE_LEN];
+        let mut ciphertext = pkcs8.as_ref().to_vec();
+        signer
+            .cipher(&salt)
+            .unwrap()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from("worker-key".as_bytes()),
+                &mut ciphertext,
+            )
+            .unwrap();
+        let encrypted = EncryptedKey {
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        };
+
+        assert!(signer.decrypt("worker-key", 1, &encrypted).is_ok());
+        assert!(signer.decrypt("worker-key", 2, &encrypted).is_err());
+    }
+
+    #[test]
+    fn unversioned_keystore_files_are_read() {
+        let path = keystore_path("file");
+        let key = serde_json::json!({ "salt": "01", "nonce": "02", "ciphertext": "03" });
+        std::fs::write(
+            &path,
+            serde_json::json!({ "old-key": key, "new-key": { "1": key, "2": key } }).to_string(),
+        )
+        .unwrap();
+
+        let keys = Keystore::read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(keys["old-key"].keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(keys["new-key"].keys().collect::<Vec<_>>(), vec![&1, &2]);
+    }
+
+    #[tokio::test]
+    async fn rotated_keys_sign_with_pinned_versions() {
+        let path = keystore_path("rotate");
+        let mut signer = signer(path.clone());
+
+        let first = signer
+            .try_add_key("ns.worker-key", "ed25519")
+            .await
+            .unwrap();
+        // Adding an existing key returns it as is.
+        assert_eq!(
+            signer
+                .try_add_key("ns.worker-key", "ed25519")
+                .await
+                .unwrap(),
+            first
+        );
+        let (version, second) = signer.rotate_key("ns.worker-key").await.unwrap();
+        assert_eq!(version, 2);
+        assert_ne!(first, second);
+
+        let (version, signature) = signer
+            .sign("ns.worker-key", b"payload", None)
+            .await
+            .unwrap();
+        assert_eq!(version, 2);
+        assert!(aws_lc_rs::signature::UnparsedPublicKey::new(
+            &aws_lc_rs::signature::ED25519,
+            &second
+        )
+        .verify(b"payload", &signature)
+        .is_ok());
+
+        let (version, _) = signer
+            .sign("ns.worker-key", b"payload", Some(1))
+            .await
+            .unwrap();
+        assert_eq!(version, 1);
+        assert!(matches!(
+            signer.sign("ns.worker-key", b"payload", Some(3)).await,
+            Err(wit::SignError::KeyNotFound(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
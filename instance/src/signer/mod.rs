@@ -0,0 +1,704 @@
+/// Signer backends for the Signing interface.
+///
+///
+/// The keys registered by each worker are tracked in a table named `signer_keys`, along with the
+/// name the backend keeps them under, so they can be found again when the worker is renamed or
+/// deleted. Every signature request is recorded in a table named
+/// `signatures`, whether it succeeded or not, along with the version of the key that was used.
+/// Both are created by the migrations under `migrations/`:
+///
+/// ```sql
+/// CREATE TABLE signer_keys (
+///     worker VARCHAR(400) NOT NULL,       -- {worker}@{uid} once the worker is purged
+///     key_name VARCHAR(255) NOT NULL,
+///     algorithm VARCHAR(50) NOT NULL,
+///     backend_key VARCHAR(600) NOT NULL,  -- name of the key in vault or the keystore
+///     created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+///     PRIMARY KEY (worker, key_name)
+/// );
+///
+/// CREATE INDEX idx_signer_keys_backend_key ON signer_keys(backend_key);
+///
+/// CREATE TABLE signatures (
+///     id BIGSERIAL PRIMARY KEY,
+///     timestamp TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+///     worker VARCHAR(320) NOT NULL,
+///     key_name VARCHAR(255) NOT NULL,
+///     key_version BIGINT,                 -- unknown when signing failed before picking a version
+///     payload_hash CHAR(64) NOT NULL,     -- hex encoded sha256 of the payload
+///     outcome VARCHAR(20) NOT NULL,       -- signed, rejected, key_not_found or error
+///     error TEXT
+/// );
+///
+/// CREATE INDEX idx_signatures_worker_timestamp ON signatures(worker, timestamp DESC);
+/// ```
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aws_lc_rs::digest;
+use balius_runtime::sign::SignerProvider;
+use balius_runtime::wit::balius::app::sign as wit;
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use miette::{Context, IntoDiagnostic};
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use operator::{parse_worker_id, AllowedPayloads, BaliusWorkerSpec, SigningPolicy, WorkerKey};
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tokio_postgres::NoTls;
+
+use crate::config::{Config, SignerKind};
+
+mod local;
+mod vault;
+
+pub use local::LocalSigner;
+pub use vault::{run, VaultSession, VaultSigner};
+
+/// Name under which a new key `key_name` of worker `worker_id` is kept by the signer backends.
+/// Existing keys keep the name recorded in `signer_keys`.
+pub fn key_for_worker(worker_id: &str, key_name: &str) -> String {
+    format!("{worker_id}-{key_name}")
+}
+
+/// Name the key was kept under before workers were identified by `{namespace}.{name}`.
+pub fn legacy_key_for_worker(worker_id: &str, key_name: &str) -> Option<String> {
+    parse_worker_id(worker_id).map(|(_, name)| key_for_worker(name, key_name))
+}
+
+/// Signing settings of a worker, taken from its spec.
+#[derive(Default, Clone, Debug)]
+struct WorkerSigningSpec {
+    key_versions: BTreeMap<String, u64>,
+    policy: SigningPolicy,
+}
+
+/// Signing settings of each worker: the key versions pinned for signing, from the `keyVersions`
+/// field of its spec, and its `signingPolicy`. Keys without a pinned version sign with their
+/// latest version.
+#[derive(Default, Clone, Debug)]
+pub struct WorkerSigning(Arc<RwLock<HashMap<String, WorkerSigningSpec>>>);
+impl WorkerSigning {
+    pub async fn set(&self, worker_id: &str, spec: &BaliusWorkerSpec) {
+        let spec = WorkerSigningSpec {
+            key_versions: spec.key_versions.clone().unwrap_or_default(),
+            policy: spec.signing_policy.clone().unwrap_or_default(),
+        };
+        self.0.write().await.insert(worker_id.to_string(), spec);
+    }
+
+    pub async fn remove(&self, worker_id: &str) {
+        self.0.write().await.remove(worker_id);
+    }
+
+    pub async fn key_version(&self, worker_id: &str, key_name: &str) -> Option<u64> {
+        self.0
+            .read()
+            .await
+            .get(worker_id)
+            .and_then(|spec| spec.key_versions.get(key_name).copied())
+    }
+
+    pub async fn policy(&self, worker_id: &str) -> SigningPolicy {
+        self.0
+            .read()
+            .await
+            .get(worker_id)
+            .map(|spec| spec.policy.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Length of a Cardano transaction body hash (blake2b-256).
+const TX_HASH_LENGTH: usize = 32;
+const SIGNING_WINDOW: Duration = Duration::from_secs(60);
+
+/// Signatures made by a worker in the current one minute window.
+struct SigningWindow {
+    start: Instant,
+    signatures: u32,
+}
+impl Default for SigningWindow {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            signatures: 0,
+        }
+    }
+}
+
+/// A recorded signature request, as returned by the admin API.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignatureRecord {
+    pub id: i64,
+    pub timestamp: String,
+    pub worker: String,
+    pub key_name: String,
+    pub key_version: Option<i64>,
+    pub payload_hash: String,
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+/// Filters for [`list_signatures`].
+#[derive(Debug, Default)]
+pub struct SignatureQuery {
+    pub key_name: Option<String>,
+    pub outcome: Option<String>,
+    /// Only return records with an id lower than this one, to page backwards.
+    pub before: Option<i64>,
+    pub limit: i64,
+}
+
+/// List the signature requests of a worker, newest first.
+pub async fn list_signatures(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    worker_id: &str,
+    query: &SignatureQuery,
+) -> miette::Result<Vec<SignatureRecord>> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let rows = conn
+        .query(
+            "SELECT id, timestamp::TEXT, worker, key_name, key_version, payload_hash, outcome, error
+             FROM signatures
+             WHERE worker = $1::TEXT
+               AND ($2::TEXT IS NULL OR key_name = $2::TEXT)
+               AND ($3::TEXT IS NULL OR outcome = $3::TEXT)
+               AND ($4::BIGINT IS NULL OR id < $4::BIGINT)
+             ORDER BY id DESC
+             LIMIT $5::BIGINT",
+            &[
+                &worker_id,
+                &query.key_name,
+                &query.outcome,
+                &query.before,
+                &query.limit,
+            ],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying signatures")?;
+
+    Ok(rows
+        .iter()
+        .map(|row| SignatureRecord {
+            id: row.get(0),
+            timestamp: row.get(1),
+            worker: row.get(2),
+            key_name: row.get(3),
+            key_version: row.get(4),
+            payload_hash: row.get(5),
+            outcome: row.get(6),
+            error: row.get(7),
+        })
+        .collect())
+}
+
+struct SignerMetrics {
+    signatures: Counter<u64>,
+}
+impl Default for SignerMetrics {
+    fn default() -> Self {
+        let meter = global::meter("baliusd");
+        Self {
+            signatures: meter
+                .u64_counter("worker_signatures")
+                .with_description("Signature requests made by workers, by outcome")
+                .build(),
+        }
+    }
+}
+
+/// Signer backend selected by the `signer` config key.
+enum Backend {
+    Vault(VaultSigner),
+    Local(LocalSigner),
+}
+
+pub struct Signer {
+    backend: Backend,
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    signing: WorkerSigning,
+    windows: HashMap<String, SigningWindow>,
+    /// Keys that failed to be added, by worker, until the worker's registration checks them.
+    key_errors: HashMap<String, String>,
+    metrics: SignerMetrics,
+}
+impl Signer {
+    pub async fn try_new(
+        config: &Config,
+        pool: &Pool<PostgresConnectionManager<NoTls>>,
+    ) -> miette::Result<Self> {
+        let backend = match config.signer.clone().unwrap_or_default() {
+            SignerKind::Vault => {
+                let session = VaultSession::try_new(config)
+                    .await
+                    .context("creating vault session")?;
+                Backend::Vault(VaultSigner::new(
+                    session,
+                    config
+                        .vault_transit_mount
+                        .as_deref()
+                        .unwrap_or(vault::DEFAULT_TRANSIT_MOUNT),
+                ))
+            }
+            SignerKind::Local => {
+                let Some(keystore) = &config.keystore else {
+                    miette::bail!("keystore must be set to use the local signer");
+                };
+                Backend::Local(
+                    LocalSigner::try_new(keystore, pool).context("creating local signer")?,
+                )
+            }
+        };
+
+        Ok(Self {
+            backend,
+            pool: pool.clone(),
+            signing: WorkerSigning::default(),
+            windows: HashMap::new(),
+            key_errors: HashMap::new(),
+            metrics: SignerMetrics::default(),
+        })
+    }
+
+    /// Vault session whose token has to be kept alive, if signing with vault.
+    pub fn vault_session(&self) -> Option<VaultSession> {
+        match &self.backend {
+            Backend::Vault(signer) => Some(signer.session().clone()),
+            Backend::Local(_) => None,
+        }
+    }
+
+    pub fn worker_signing(&self) -> WorkerSigning {
+        self.signing.clone()
+    }
+
+    /// Error of the last key the worker failed to add, if any, so its registration can fail.
+    pub fn take_key_error(&mut self, worker_id: &str) -> Option<String> {
+        self.key_errors.remove(worker_id)
+    }
+
+    /// Check a signature request against the worker's signing policy. Requests that pass count
+    /// towards the worker's rate limit.
+    fn check_policy(
+        &mut self,
+        worker_id: &str,
+        policy: &SigningPolicy,
+        payload: &[u8],
+    ) -> Result<(), String> {
+        if policy.allowed_payloads.unwrap_or_default() == AllowedPayloads::TxHash
+            && payload.len() != TX_HASH_LENGTH
+        {
+            return Err(format!(
+                "only transaction hashes can be signed, got a {} bytes payload",
+                payload.len()
+            ));
+        }
+
+        if let Some(max) = policy.max_signatures_per_minute {
+            let window = self.windows.entry(worker_id.to_string()).or_default();
+            if window.start.elapsed() >= SIGNING_WINDOW {
+                *window = SigningWindow::default();
+            }
+            if window.signatures >= max {
+                return Err(format!("limit of {max} signatures per minute reached"));
+            }
+            window.signatures += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Add a new version to a worker key. Returns the new version and its public key.
+    pub async fn rotate_key(
+        &mut self,
+        worker_id: &str,
+        key_name: &str,
+    ) -> Result<(u64, Vec<u8>), wit::SignError> {
+        let name = self.backend_key(worker_id, key_name).await?;
+        let result = match &mut self.backend {
+            Backend::Vault(signer) => signer.rotate_key(&name).await,
+            Backend::Local(signer) => signer.rotate_key(&name).await,
+        };
+
+        if let Ok((version, _)) = &result {
+            tracing::info!(worker_id, key_name, version, "rotated signing key");
+        }
+        result
+    }
+
+    /// Keys registered by a worker, with the public key of their latest version.
+    pub async fn list_keys(&mut self, worker_id: &str) -> Result<Vec<WorkerKey>, wit::SignError> {
+        let conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+        let rows = conn
+            .query(
+                "SELECT key_name, algorithm, backend_key FROM signer_keys
+                 WHERE worker = $1::TEXT ORDER BY key_name",
+                &[&worker_id],
+            )
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+
+        let mut keys = Vec::with_capacity(rows.len());
+        for row in rows {
+            let name: String = row.get(0);
+            let algorithm: String = row.get(1);
+            let backend_key: String = row.get(2);
+
+            let latest = match &mut self.backend {
+                Backend::Vault(signer) => signer.latest_public_key(&backend_key).await,
+                Backend::Local(signer) => signer.latest_public_key(&backend_key).await,
+            }?;
+            let Some((version, public_key)) = latest else {
+                tracing::warn!(
+                    worker_id,
+                    key_name = name,
+                    "registered key missing in backend"
+                );
+                continue;
+            };
+
+            keys.push(WorkerKey {
+                name,
+                algorithm,
+                version,
+                public_key: hex::encode(public_key),
+            });
+        }
+
+        Ok(keys)
+    }
+
+    /// Delete every key registered by a worker from the backend, along with its registry rows.
+    pub async fn delete_worker_keys(&mut self, worker_id: &str) -> Result<(), wit::SignError> {
+        let conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+        let keys: Vec<(String, String)> = conn
+            .query(
+                "SELECT key_name, backend_key FROM signer_keys WHERE worker = $1::TEXT",
+                &[&worker_id],
+            )
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+
+        for (key_name, name) in keys {
+            match &mut self.backend {
+                Backend::Vault(signer) => signer.delete_key(&name).await,
+                Backend::Local(signer) => signer.delete_key(&name).await,
+            }?;
+            conn.execute(
+                "DELETE FROM signer_keys WHERE worker = $1::TEXT AND key_name = $2::TEXT",
+                &[&worker_id, &key_name],
+            )
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+            tracing::info!(worker_id, key_name, "deleted signing key");
+        }
+
+        Ok(())
+    }
+
+    /// Name of the worker key in the backend: the registered one, or the default for new keys.
+    async fn backend_key(&self, worker_id: &str, key_name: &str) -> Result<String, wit::SignError> {
+        Ok(self
+            .registered_key(worker_id, key_name)
+            .await?
+            .unwrap_or_else(|| key_for_worker(worker_id, key_name)))
+    }
+
+    async fn registered_key(
+        &self,
+        worker_id: &str,
+        key_name: &str,
+    ) -> Result<Option<String>, wit::SignError> {
+        let conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+        let row = conn
+            .query_opt(
+                "SELECT backend_key FROM signer_keys WHERE worker = $1::TEXT AND key_name = $2::TEXT",
+                &[&worker_id, &key_name],
+            )
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Name in the backend of a key the worker registers for the first time. Keys created before
+    /// `signer_keys` existed are kept under the worker's bare name; they are only taken over by
+    /// the worker the bare name was handed over to, see [`crate::migration`].
+    async fn new_backend_key(
+        &mut self,
+        worker_id: &str,
+        key_name: &str,
+    ) -> Result<String, wit::SignError> {
+        let name = key_for_worker(worker_id, key_name);
+        let Some(legacy) = legacy_key_for_worker(worker_id, key_name) else {
+            return Ok(name);
+        };
+
+        let conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?;
+        let inherited = conn
+            .query_opt(
+                "SELECT 1 FROM legacy_workers WHERE worker = $1::TEXT",
+                &[&worker_id],
+            )
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?
+            .is_some();
+        if !inherited {
+            return Ok(name);
+        }
+        let claimed = conn
+            .query_opt(
+                "SELECT 1 FROM signer_keys WHERE backend_key = $1::TEXT LIMIT 1",
+                &[&legacy],
+            )
+            .await
+            .map_err(|err| wit::SignError::Internal(err.to_string()))?
+            .is_some();
+        drop(conn);
+        if claimed {
+            return Ok(name);
+        }
+
+        let existing = match &mut self.backend {
+            Backend::Vault(signer) => signer.latest_public_key(&legacy).await?,
+            Backend::Local(signer) => signer.latest_public_key(&legacy).await?,
+        };
+        match existing {
+            Some(_) => {
+                tracing::info!(
+                    worker_id,
+                    key_name,
+                    legacy,
+                    "taking over legacy signing key"
+                );
+                Ok(legacy)
+            }
+            None => Ok(name),
+        }
+    }
+
+    async fn register_key(
+        &self,
+        worker_id: &str,
+        key_name: &str,
+        algorithm: &str,
+        backend_key: &str,
+    ) -> Result<(), String> {
+        let conn = self.pool.get().await.map_err(|err| err.to_string())?;
+        conn.execute(
+            "INSERT INTO signer_keys (worker, key_name, algorithm, backend_key)
+             VALUES ($1::TEXT, $2::TEXT, $3::TEXT, $4::TEXT)
+             ON CONFLICT (worker, key_name) DO NOTHING;",
+            &[&worker_id, &key_name, &algorithm, &backend_key],
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    /// Count a signature request in the metrics and record it.
+    async fn audit(
+        &self,
+        worker_id: &str,
+        key_name: &str,
+        key_version: Option<u64>,
+        payload: &[u8],
+        outcome: &str,
+        error: Option<String>,
+    ) {
+        self.metrics.signatures.add(
+            1,
+            &[
+                KeyValue::new("worker", worker_id.to_string()),
+                KeyValue::new("outcome", outcome.to_string()),
+            ],
+        );
+
+        if let Err(err) = self
+            .record_signature(worker_id, key_name, key_version, payload, outcome, error)
+            .await
+        {
+            tracing::error!(
+                worker_id,
+                key_name,
+                outcome,
+                err,
+                "failed to record signature"
+            );
+        }
+    }
+
+    async fn record_signature(
+        &self,
+        worker_id: &str,
+        key_name: &str,
+        key_version: Option<u64>,
+        payload: &[u8],
+        outcome: &str,
+        error: Option<String>,
+    ) -> Result<(), String> {
+        let payload_hash = hex::encode(digest::digest(&digest::SHA256, payload));
+        let conn = self.pool.get().await.map_err(|err| err.to_string())?;
+        conn.execute(
+            "INSERT INTO signatures (worker, key_name, key_version, payload_hash, outcome, error)
+             VALUES ($1::TEXT, $2::TEXT, $3::BIGINT, $4::TEXT, $5::TEXT, $6::TEXT);",
+            &[
+                &worker_id,
+                &key_name,
+                &key_version.map(|version| version as i64),
+                &payload_hash,
+                &outcome,
+                &error,
+            ],
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl SignerProvider for Signer {
+    /// Registering a key is idempotent: for an existing key the public key of its latest version
+    /// is returned. The runtime interface has no room for errors here, so failures are kept for
+    /// [`Signer::take_key_error`] and the worker fails to load instead of using the empty key
+    /// returned.
+    #[tracing::instrument("signer.add_key", skip_all, fields(worker = worker_id, key = %key_name))]
+    async fn add_key(&mut self, worker_id: &str, key_name: String, algorithm: String) -> Vec<u8> {
+        let name = match self.registered_key(worker_id, &key_name).await {
+            Ok(Some(name)) => Ok(name),
+            Ok(None) => self.new_backend_key(worker_id, &key_name).await,
+            Err(err) => Err(err),
+        };
+        let name = match name {
+            Ok(name) => name,
+            Err(err) => {
+                tracing::error!(worker_id, key_name, err =? err, "failed to look up signing key");
+                self.key_errors.insert(
+                    worker_id.to_string(),
+                    format!("failed to look up signing key {key_name}: {err:?}"),
+                );
+                return vec![];
+            }
+        };
+        let result = match &mut self.backend {
+            Backend::Vault(signer) => signer.try_add_key(&name, &algorithm).await,
+            Backend::Local(signer) => signer.try_add_key(&name, &algorithm).await,
+        };
+
+        match result {
+            Ok(public_key) => {
+                if let Err(err) = self
+                    .register_key(worker_id, &key_name, &algorithm, &name)
+                    .await
+                {
+                    // Unregistered keys are never listed, rotated or deleted with the worker.
+                    tracing::error!(worker_id, key_name, err, "failed to register signing key");
+                    self.key_errors.insert(
+                        worker_id.to_string(),
+                        format!("failed to register signing key {key_name}: {err}"),
+                    );
+                    return vec![];
+                }
+                public_key
+            }
+            Err(err) => {
+                tracing::error!(worker_id, key_name, err =? err, "failed to add signing key");
+                self.key_errors.insert(
+                    worker_id.to_string(),
+                    format!("failed to add signing key {key_name}: {err:?}"),
+                );
+                vec![]
+            }
+        }
+    }
+
+    #[tracing::instrument("signer.sign_payload", skip_all, fields(worker = worker_id, key = %key_name))]
+    async fn sign_payload(
+        &mut self,
+        worker_id: &str,
+        key_name: String,
+        payload: wit::Payload,
+    ) -> Result<wit::Signature, wit::SignError> {
+        let pinned = self.signing.key_version(worker_id, &key_name).await;
+        let policy = self.signing.policy(worker_id).await;
+        if let Err(violation) = self.check_policy(worker_id, &policy, &payload) {
+            let error = format!("signing policy violation: {violation}");
+            self.audit(
+                worker_id,
+                &key_name,
+                pinned,
+                &payload,
+                "rejected",
+                Some(error.clone()),
+            )
+            .await;
+            return Err(wit::SignError::Internal(error));
+        }
+
+        let result = match self.backend_key(worker_id, &key_name).await {
+            Ok(name) => match &mut self.backend {
+                Backend::Vault(signer) => signer.sign(&name, &payload, pinned).await,
+                Backend::Local(signer) => signer.sign(&name, &payload, pinned).await,
+            },
+            Err(err) => Err(err),
+        };
+
+        let (version, outcome, error) = match &result {
+            Ok((version, _)) => (Some(*version), "signed", None),
+            Err(wit::SignError::KeyNotFound(x)) => (pinned, "key_not_found", Some(x.clone())),
+            Err(err) => (pinned, "error", Some(format!("{err:?}"))),
+        };
+        self.audit(worker_id, &key_name, version, &payload, outcome, error)
+            .await;
+
+        result.map(|(_, signature)| signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_for_worker("ns.worker", "main"), "ns.worker-main");
+        assert_eq!(
+            legacy_key_for_worker("ns.worker", "main").as_deref(),
+            Some("worker-main")
+        );
+        // Only the namespace is stripped, names can contain dots.
+        assert_eq!(
+            legacy_key_for_worker("ns.my.worker", "main").as_deref(),
+            Some("my.worker-main")
+        );
+        assert_eq!(legacy_key_for_worker("worker", "main"), None);
+    }
+}
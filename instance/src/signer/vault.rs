@@ -0,0 +1,631 @@
+use balius_runtime::wit::balius::app::sign as wit;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use miette::{Context, IntoDiagnostic};
+use opentelemetry::global;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use vaultrs::api::transit::requests::{
+    CreateKeyRequest, ExportKeyType, ExportVersion, SignDataRequest, UpdateKeyConfigurationRequest,
+};
+use vaultrs::api::transit::KeyType;
+use vaultrs::api::AuthInfo;
+use vaultrs::auth::{approle, kubernetes};
+use vaultrs::client::{Client as _, VaultClient, VaultClientSettingsBuilder};
+use vaultrs::error::ClientError;
+use vaultrs::token;
+use vaultrs::transit::{data, key};
+
+use crate::{
+    config::{Config, VaultAuth},
+    health::Flag,
+};
+
+pub const DEFAULT_TRANSIT_MOUNT: &str = "transit";
+
+const DEFAULT_SERVICE_ACCOUNT_TOKEN_PATH: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Tokens expiring sooner than this are reported as unhealthy.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RENEW_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What is known about the lifetime of the current token.
+#[derive(Clone, Copy, Debug)]
+struct TokenLease {
+    /// When the token expires, `None` for tokens that don't expire.
+    expires_at: Option<Instant>,
+    renewable: bool,
+}
+impl TokenLease {
+    fn new(ttl_seconds: u64, renewable: bool) -> Self {
+        Self {
+            expires_at: (ttl_seconds > 0)
+                .then(|| Instant::now() + Duration::from_secs(ttl_seconds)),
+            renewable,
+        }
+    }
+}
+impl Default for TokenLease {
+    /// Until vault says otherwise, tokens are assumed to be renewable and not to expire.
+    fn default() -> Self {
+        Self {
+            expires_at: None,
+            renewable: true,
+        }
+    }
+}
+
+/// Vault client shared between the signer and the token renewer, so that a token obtained by
+/// logging in again is picked up by the signer.
+#[derive(Clone)]
+pub struct VaultSession {
+    client: Arc<RwLock<VaultClient>>,
+    auth: VaultAuth,
+    lease: Arc<std::sync::Mutex<TokenLease>>,
+    /// Whether the token renewer's last attempt went through.
+    renewed: Flag,
+}
+impl VaultSession {
+    pub async fn try_new(config: &Config) -> miette::Result<Self> {
+        let Some(address) = &config.vault_address else {
+            miette::bail!("vault_address must be set to use the vault signer");
+        };
+        let auth = config.vault_auth.clone().unwrap_or_default();
+
+        let mut settings = VaultClientSettingsBuilder::default();
+        settings
+            .address(address)
+            .verify(!config.vault_skip_verify.unwrap_or(false))
+            .namespace(config.vault_namespace.clone());
+        if let Some(ca_cert) = &config.vault_ca_cert {
+            let Some(ca_cert) = ca_cert.to_str() else {
+                miette::bail!("vault_ca_cert must be a valid UTF-8 path");
+            };
+            settings.ca_certs(vec![ca_cert.to_string()]);
+        }
+        if config.vault_skip_verify.unwrap_or(false) {
+            tracing::warn!("vault TLS certificate verification is disabled");
+        }
+        if let VaultAuth::Token = auth {
+            match &config.vault_token {
+                Some(token) => settings.token(token),
+                None => miette::bail!("vault_token must be set to use token authentication"),
+            };
+        }
+
+        // Create a client
+        let client = VaultClient::new(
+            settings
+                .build()
+                .into_diagnostic()
+                .context("creating vault client settings")?,
+        )
+        .into_diagnostic()
+        .context("creating vault client")?;
+
+        let session = Self {
+            client: Arc::new(RwLock::new(client)),
+            auth,
+            lease: Default::default(),
+            renewed: Flag::default(),
+        };
+        session.login().await?;
+        session.renewed.set(true);
+        if !session.can_login() {
+            if let Err(err) = session.lookup().await {
+                tracing::warn!(err =? err, "failed to look up vault token, assuming it's renewable");
+            }
+        }
+
+        Ok(session)
+    }
+
+    fn lease(&self) -> TokenLease {
+        *self.lease.lock().unwrap()
+    }
+
+    fn set_lease(&self, lease: TokenLease) {
+        *self.lease.lock().unwrap() = lease;
+    }
+
+    /// Time left before the token expires, `None` if it doesn't expire.
+    pub fn token_ttl(&self) -> Option<Duration> {
+        self.lease()
+            .expires_at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the token is far enough from expiring.
+    pub fn is_healthy(&self) -> bool {
+        self.token_ttl().is_none_or(|ttl| ttl > TOKEN_EXPIRY_MARGIN)
+    }
+
+    /// When to renew the token next: two thirds into its lease, and at least every
+    /// `max_interval`.
+    fn next_renewal(&self, max_interval: Duration) -> Duration {
+        match self.token_ttl() {
+            Some(ttl) => {
+                (ttl * 2 / 3).clamp(MIN_RENEW_INTERVAL, max_interval.max(MIN_RENEW_INTERVAL))
+            }
+            None => max_interval,
+        }
+    }
+
+    /// Learn the lifetime of a static token.
+    async fn lookup(&self) -> Result<(), ClientError> {
+        let client = self.client().await;
+        let response = with_retries(|| token::lookup_self(&*client)).await?;
+        self.set_lease(TokenLease::new(response.ttl, response.renewable));
+        Ok(())
+    }
+
+    /// Whether the token renewer's last attempt went through and the token is far enough from
+    /// expiring. Vault isn't asked: failures show once the renewer next runs, which it does
+    /// with backoff after a failure.
+    pub fn is_available(&self) -> bool {
+        self.renewed.get() && self.is_healthy()
+    }
+
+    /// Extend the token's lease, or log in again once it can't be extended anymore.
+    async fn refresh(&self, increment: &str) -> miette::Result<()> {
+        if self.lease().renewable {
+            let result = {
+                let client = self.client().await;
+                with_retries(|| token::renew_self(&*client, Some(increment))).await
+            };
+            match result {
+                Ok(info) => {
+                    self.set_lease(TokenLease::new(info.lease_duration, info.renewable));
+                    tracing::debug!(lease_duration = info.lease_duration, "vault token renewed");
+
+                    // Vault caps the lease at the token's max TTL. A lease shorter than asked for
+                    // means the token is about to expire for good, so a new one is obtained while
+                    // this one still works.
+                    let capped = parse_increment(increment)
+                        .is_some_and(|increment| info.lease_duration < increment);
+                    if !(self.can_login() && (capped || !info.renewable)) {
+                        return Ok(());
+                    }
+                    tracing::info!(
+                        lease_duration = info.lease_duration,
+                        "vault token close to its max TTL, logging in again"
+                    );
+                }
+                // Tokens obtained by logging in expire for good once they reach their max TTL,
+                // at which point the only way forward is to log in again.
+                Err(err) if self.can_login() => {
+                    tracing::warn!(err =? err, "failed to renew vault token, logging in again");
+                }
+                Err(err) => return Err(err).into_diagnostic().context("renewing vault token"),
+            }
+        }
+
+        self.login().await
+    }
+
+    pub async fn client(&self) -> RwLockReadGuard<'_, VaultClient> {
+        self.client.read().await
+    }
+
+    /// Whether a new token can be obtained by logging in, as opposed to a static token.
+    fn can_login(&self) -> bool {
+        !matches!(self.auth, VaultAuth::Token)
+    }
+
+    /// Log in with the configured auth method and start using the new token. Does nothing for
+    /// static tokens.
+    async fn login(&self) -> miette::Result<()> {
+        let info: AuthInfo = match &self.auth {
+            VaultAuth::Token => return Ok(()),
+            VaultAuth::Kubernetes {
+                role,
+                mount,
+                jwt_path,
+            } => {
+                let jwt = std::fs::read_to_string(
+                    jwt_path
+                        .as_deref()
+                        .unwrap_or(DEFAULT_SERVICE_ACCOUNT_TOKEN_PATH.as_ref()),
+                )
+                .into_diagnostic()
+                .context("reading service account token")?;
+                kubernetes::login(
+                    &*self.client().await,
+                    mount.as_deref().unwrap_or("kubernetes"),
+                    role,
+                    jwt.trim(),
+                )
+                .await
+                .into_diagnostic()
+                .context("logging in to vault with kubernetes auth")?
+            }
+            VaultAuth::AppRole {
+                role_id,
+                secret_id,
+                mount,
+            } => approle::login(
+                &*self.client().await,
+                mount.as_deref().unwrap_or("approle"),
+                role_id,
+                secret_id,
+            )
+            .await
+            .into_diagnostic()
+            .context("logging in to vault with approle auth")?,
+        };
+
+        self.client.write().await.set_token(&info.client_token);
+        self.set_lease(TokenLease::new(info.lease_duration, info.renewable));
+        tracing::info!(lease_duration = info.lease_duration, "logged in to vault");
+        Ok(())
+    }
+}
+
+pub struct VaultSigner {
+    session: VaultSession,
+    mount: String,
+}
+impl VaultSigner {
+    /// Sign with the transit secrets engine mounted at `mount`.
+    pub fn new(session: VaultSession, mount: impl Into<String>) -> Self {
+        Self {
+            session,
+            mount: mount.into(),
+        }
+    }
+
+    pub fn session(&self) -> &VaultSession {
+        &self.session
+    }
+}
+
+/// Seconds in a renewal increment such as `3600`, `90s`, `30m` or `1h`.
+fn parse_increment(increment: &str) -> Option<u64> {
+    let increment = increment.trim();
+    let (value, unit) = match increment.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => increment.split_at(at),
+        None => (increment, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    value.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Attempts made for a vault call before giving up on transient errors.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Whether the error is worth retrying: connection failures and vault server errors.
+fn is_transient(err: &ClientError) -> bool {
+    match err {
+        ClientError::APIError { code, errors: _ } => *code >= 500,
+        ClientError::RestClientError { .. } => true,
+        _ => false,
+    }
+}
+
+fn is_not_found(err: &ClientError) -> bool {
+    match err {
+        ClientError::APIError { code, errors } => {
+            *code == 404 || errors.iter().any(|x| x.contains("not found"))
+        }
+        _ => false,
+    }
+}
+
+fn to_sign_error(err: ClientError) -> wit::SignError {
+    if is_not_found(&err) {
+        wit::SignError::KeyNotFound(err.to_string())
+    } else {
+        wit::SignError::Internal(err.to_string())
+    }
+}
+
+/// Run a vault call, retrying with exponential backoff while it fails with transient errors.
+async fn with_retries<T, F, Fut>(mut call: F) -> Result<T, ClientError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, ClientError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                tracing::warn!(err =? err, attempt, "vault call failed, retrying");
+                tokio::time::sleep(Duration::from_millis(200 * 2u64.pow(attempt))).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+impl VaultSigner {
+    /// Latest version of `vault_key` along with its public key, or `None` if the key doesn't
+    /// exist.
+    pub async fn latest_public_key(
+        &self,
+        vault_key: &str,
+    ) -> Result<Option<(u64, Vec<u8>)>, wit::SignError> {
+        let guard = self.session.client().await;
+        let mount = self.mount.as_str();
+        let client: &VaultClient = &guard;
+        let response = match with_retries(|| {
+            key::export(
+                client,
+                mount,
+                vault_key,
+                ExportKeyType::PublicKey,
+                ExportVersion::Latest,
+            )
+        })
+        .await
+        {
+            Ok(response) => response,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(wit::SignError::Internal(err.to_string())),
+        };
+
+        // Exporting the latest version returns a single entry, keyed by its version number.
+        let latest = response
+            .keys
+            .iter()
+            .filter_map(|(version, key)| version.parse::<u64>().ok().map(|v| (v, key)))
+            .max_by_key(|(version, _)| *version);
+
+        match latest {
+            Some((version, key)) => STANDARD
+                .decode(key)
+                .map(|key| Some((version, key)))
+                .map_err(|err| wit::SignError::Internal(format!("invalid public key: {err}"))),
+            None => Err(wit::SignError::Internal(format!(
+                "no public key exported for {vault_key}"
+            ))),
+        }
+    }
+
+    /// Create the key if it doesn't exist yet and return the public key of its latest version.
+    pub async fn try_add_key(
+        &mut self,
+        vault_key: &str,
+        algorithm: &str,
+    ) -> Result<Vec<u8>, wit::SignError> {
+        if algorithm != "ed25519" {
+            return Err(wit::SignError::Internal(format!(
+                "unsupported algorithm: {algorithm}"
+            )));
+        }
+
+        if let Some((_, public_key)) = self.latest_public_key(vault_key).await? {
+            return Ok(public_key);
+        }
+
+        // Create an encryption key using the /transit backend
+        let guard = self.session.client().await;
+        let mount = self.mount.as_str();
+        let (client, name): (&VaultClient, &str) = (&guard, vault_key);
+        with_retries(|| async move {
+            key::create(
+                client,
+                mount,
+                name,
+                Some(CreateKeyRequest::builder().key_type(KeyType::Ed25519)),
+            )
+            .await
+        })
+        .await
+        .map_err(|err| wit::SignError::Internal(format!("failed to create key: {err}")))?;
+
+        self.latest_public_key(vault_key)
+            .await?
+            .map(|(_, public_key)| public_key)
+            .ok_or(wit::SignError::KeyNotFound(vault_key.to_string()))
+    }
+
+    /// Add a new version to the key, which becomes the one used for signing unless a version is
+    /// pinned. Returns the new version and its public key.
+    pub async fn rotate_key(&mut self, vault_key: &str) -> Result<(u64, Vec<u8>), wit::SignError> {
+        {
+            let guard = self.session.client().await;
+            let mount = self.mount.as_str();
+            let client: &VaultClient = &guard;
+            with_retries(|| key::rotate(client, mount, vault_key))
+                .await
+                .map_err(to_sign_error)?;
+        }
+
+        self.latest_public_key(vault_key)
+            .await?
+            .ok_or(wit::SignError::KeyNotFound(vault_key.to_string()))
+    }
+
+    /// Delete every version of the key. Deleting a key that doesn't exist is not an error.
+    pub async fn delete_key(&mut self, vault_key: &str) -> Result<(), wit::SignError> {
+        let guard = self.session.client().await;
+        let mount = self.mount.as_str();
+        let (client, name): (&VaultClient, &str) = (&guard, vault_key);
+
+        // Transit keys can't be deleted until explicitly allowed.
+        let allowed = with_retries(|| async move {
+            key::update(
+                client,
+                mount,
+                name,
+                Some(UpdateKeyConfigurationRequest::builder().deletion_allowed(true)),
+            )
+            .await
+        })
+        .await;
+        match allowed {
+            Ok(_) => {}
+            Err(err) if is_not_found(&err) => return Ok(()),
+            Err(err) => return Err(to_sign_error(err)),
+        }
+
+        with_retries(|| key::delete(client, mount, name))
+            .await
+            .map_err(to_sign_error)
+    }
+
+    /// Sign with the given version of the key, or the latest one. Returns the version used along
+    /// with the signature.
+    pub async fn sign(
+        &mut self,
+        vault_key: &str,
+        payload: &[u8],
+        version: Option<u64>,
+    ) -> Result<(u64, wit::Signature), wit::SignError> {
+        let input = STANDARD.encode(payload);
+        let guard = self.session.client().await;
+        let mount = self.mount.as_str();
+        let (client, name, input): (&VaultClient, &str, &str) = (&guard, vault_key, &input);
+        let response = with_retries(|| async move {
+            let mut request = SignDataRequest::builder();
+            if let Some(version) = version {
+                request.key_version(version);
+            }
+            data::sign(client, mount, name, input, Some(&mut request)).await
+        })
+        .await
+        .map_err(to_sign_error)?;
+
+        parse_signature(&response.signature)
+    }
+}
+
+/// Split a vault signature, formatted as `vault:v{version}:{base64 signature}`.
+fn parse_signature(signature: &str) -> Result<(u64, wit::Signature), wit::SignError> {
+    let invalid = || wit::SignError::Internal(format!("invalid vault signature: {signature}"));
+
+    let mut parts = signature.splitn(3, ':');
+    let (Some("vault"), Some(version), Some(encoded)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let version = version
+        .strip_prefix('v')
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or_else(invalid)?;
+    let signature = STANDARD.decode(encoded).map_err(|_| invalid())?;
+
+    Ok((version, signature))
+}
+
+#[instrument("vault-token-renewer", skip_all)]
+pub async fn run(
+    config: &Config,
+    session: Option<VaultSession>,
+    cancel: CancellationToken,
+) -> miette::Result<()> {
+    // Nothing to renew when signing without vault.
+    let Some(session) = session else {
+        return Ok(());
+    };
+
+    let max_interval = Duration::from_secs(config.vault_token_renew_seconds.unwrap_or(3600));
+    let increment = config
+        .vault_token_renew_increment
+        .clone()
+        .unwrap_or("1h".into());
+    let ttl_gauge = global::meter("baliusd")
+        .u64_gauge("vault_token_ttl_seconds")
+        .with_description("Time left before the vault token expires")
+        .build();
+
+    // Failures are retried with backoff rather than returned, as they would bring the whole
+    // instance down while the token may still be valid for a while.
+    let mut failures = 0;
+    loop {
+        let wait = match failures {
+            0 => session.next_renewal(max_interval),
+            n => Duration::from_secs(2u64.saturating_pow(n)).min(MAX_RENEW_BACKOFF),
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {
+                let result = session.refresh(&increment).await;
+                session.renewed.set(result.is_ok());
+                match result {
+                    Ok(()) => failures = 0,
+                    Err(err) => {
+                        failures += 1;
+                        tracing::error!(err = err.to_string(), failures, "failed to refresh vault token");
+                    }
+                }
+
+                if let Some(ttl) = session.token_ttl() {
+                    ttl_gauge.record(ttl.as_secs(), &[]);
+                }
+                if !session.is_healthy() {
+                    tracing::warn!(ttl =? session.token_ttl(), "vault token is close to expiring");
+                }
+            }
+            _ = cancel.cancelled() => {
+                tracing::warn!("received cancellation");
+                return Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_increment_units() {
+        assert_eq!(parse_increment("3600"), Some(3600));
+        assert_eq!(parse_increment("90s"), Some(90));
+        assert_eq!(parse_increment("30m"), Some(1800));
+        assert_eq!(parse_increment("1h"), Some(3600));
+        assert_eq!(parse_increment("2d"), Some(172800));
+        assert_eq!(parse_increment("1h30m"), None);
+        assert_eq!(parse_increment("h"), None);
+    }
+
+    #[test]
+    fn parse_vault_signatures() {
+        assert_eq!(
+            parse_signature("vault:v3:AQID").unwrap(),
+            (3, vec![1, 2, 3])
+        );
+        for invalid in [
+            "vault:3:AQID",
+            "vault:vx:AQID",
+            "transit:v1:AQID",
+            "vault:v1",
+            "vault:v1:not base64!",
+        ] {
+            assert!(parse_signature(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn transient_errors() {
+        let server = ClientError::APIError {
+            code: 503,
+            errors: vec![],
+        };
+        let denied = ClientError::APIError {
+            code: 403,
+            errors: vec!["permission denied".into()],
+        };
+        assert!(is_transient(&server));
+        assert!(!is_transient(&denied));
+        assert!(is_not_found(&ClientError::APIError {
+            code: 400,
+            errors: vec!["encryption key not found".into()],
+        }));
+        assert!(!is_not_found(&denied));
+    }
+}
@@ -6,7 +6,7 @@
 ///
 /// ```sql
 /// CREATE TABLE cursors (
-///     worker VARCHAR(100) PRIMARY KEY,
+///     worker VARCHAR(320) PRIMARY KEY,
 ///     logseq BIGINT NOT NULL
 /// );
 ///
@@ -15,20 +15,35 @@
 ///     logentry BYTEA NOT NULL
 /// );
 /// ```
+///
+/// Committing the workers' cursors also notifies the blocks undone and applied for the shard on
+/// the `block_events` channel once the transaction is committed, see [`crate::events`].
 use balius_runtime::{
     store::{AtomicUpdate, LogEntry, LogSeq, StoreTrait},
     AtomicUpdateTrait, Block, ChainPoint, Error,
 };
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
+use miette::{Context, IntoDiagnostic};
 use prost::Message;
-use std::{collections::BTreeSet, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
 use tokio::sync::Mutex;
 use tokio_postgres::NoTls;
 
+use crate::{
+    events::{BlockEvent, Notification, CHANNEL},
+    logging::CurrentSlot,
+};
+
 pub struct PostgresStore {
     pool: Pool<PostgresConnectionManager<NoTls>>,
     shard: String,
+    current_slot: CurrentSlot,
+    /// Events of the last block written ahead, notified as workers commit it.
+    last_events: Option<(LogSeq, Arc<Vec<BlockEvent>>)>,
 }
 
 impl PostgresStore {
@@ -36,12 +51,94 @@ impl PostgresStore {
         Self {
             pool: pool.clone(),
             shard: shard.to_string(),
+            current_slot: CurrentSlot::default(),
+            last_events: None,
         }
     }
+
+    pub fn with_current_slot(mut self, current_slot: CurrentSlot) -> Self {
+        self.current_slot = current_slot;
+        self
+    }
 }
 
 const MAX_UNDOS: usize = 50;
 
+/// Cursor of each worker of the shard, along with the slot of the block it points to.
+pub async fn worker_cursors(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    shard: &str,
+) -> miette::Result<HashMap<String, (LogSeq, Option<u64>)>> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let rows = conn
+        .query(
+            "SELECT c.worker, c.logseq, w.logentry
+             FROM cursors c
+             LEFT JOIN wal w ON w.logseq = c.logseq AND w.shard = c.shard
+             WHERE c.shard = $1::TEXT",
+            &[&shard],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying cursors")?;
+
+    rows.iter()
+        .map(|row| {
+            let worker: String = row.get(0);
+            let seq: i64 = row.get(1);
+            let slot = match row.get::<_, Option<Vec<u8>>>(2) {
+                Some(bytes) => Some(decode_block(&bytes)?.slot()),
+                None => None,
+            };
+            Ok((worker, (seq as u64, slot)))
+        })
+        .collect()
+}
+
+/// Slot and hex encoded hash of the block the worker's cursor points to.
+pub async fn worker_chain_point(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    shard: &str,
+    worker: &str,
+) -> miette::Result<Option<(u64, String)>> {
+    let conn = pool
+        .get()
+        .await
+        .into_diagnostic()
+        .context("getting connection")?;
+    let row = conn
+        .query_opt(
+            "SELECT w.logentry
+             FROM cursors c
+             JOIN wal w ON w.logseq = c.logseq AND w.shard = c.shard
+             WHERE c.worker = $1::TEXT AND c.shard = $2::TEXT",
+            &[&worker, &shard],
+        )
+        .await
+        .into_diagnostic()
+        .context("querying cursor")?;
+
+    match row {
+        Some(row) => {
+            let block = decode_block(&row.get::<_, Vec<u8>>(0))?;
+            Ok(Some((block.slot(), hex::encode(block.hash()))))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Block applied by a `wal` entry.
+fn decode_block(logentry: &[u8]) -> miette::Result<Block> {
+    let entry = LogEntry::decode(logentry)
+        .into_diagnostic()
+        .context("decoding logentry")?;
+    Ok(Block::from_bytes(&entry.next_block))
+}
+
 #[async_trait::async_trait]
 impl StoreTrait for PostgresStore {
     async fn find_chain_point(&self, seq: LogSeq) -> Result<Option<ChainPoint>, Error> {
@@ -94,6 +191,9 @@ impl StoreTrait for PostgresStore {
         {
             Some(row) => {
                 let seq: i64 = row.get(0);
+                self.current_slot.set(next_block.slot());
+                self.last_events =
+                    Some((seq as u64, Arc::new(block_events(undo_blocks, next_block))));
                 Ok(seq as u64)
             }
             None => Err(Error::Store("failed to get logseq".to_string())),
@@ -122,8 +222,12 @@ impl StoreTrait for PostgresStore {
     }
 
     async fn start_atomic_update(&self, log_seq: LogSeq) -> Result<AtomicUpdate, Error> {
+        let events = match &self.last_events {
+            Some((seq, events)) if *seq == log_seq => events.clone(),
+            _ => Default::default(),
+        };
         Ok(AtomicUpdate::Custom(Arc::new(Mutex::new(
-            PostgresAtomicUpdate::new(&self.pool, log_seq, &self.shard),
+            PostgresAtomicUpdate::new(&self.pool, log_seq, &self.shard).with_events(events),
         ))))
     }
 
@@ -161,11 +265,26 @@ impl StoreTrait for PostgresStore {
     }
 }
 
+fn block_events(undo_blocks: &[Block], next_block: &Block) -> Vec<BlockEvent> {
+    undo_blocks
+        .iter()
+        .map(|block| BlockEvent::Rollback {
+            slot: block.slot(),
+            hash: hex::encode(block.hash()),
+        })
+        .chain(std::iter::once(BlockEvent::Applied {
+            slot: next_block.slot(),
+            hash: hex::encode(next_block.hash()),
+        }))
+        .collect()
+}
+
 pub struct PostgresAtomicUpdate {
     cache: BTreeSet<String>,
     pool: Pool<PostgresConnectionManager<NoTls>>,
     log_seq: LogSeq,
     shard: String,
+    events: Arc<Vec<BlockEvent>>,
 }
 impl PostgresAtomicUpdate {
     pub fn new(
@@ -178,8 +297,14 @@ impl PostgresAtomicUpdate {
             log_seq,
             cache: Default::default(),
             shard: shard.to_string(),
+            events: Default::default(),
         }
     }
+
+    pub fn with_events(mut self, events: Arc<Vec<BlockEvent>>) -> Self {
+        self.events = events;
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -218,6 +343,38 @@ impl AtomicUpdateTrait for PostgresAtomicUpdate {
             .await
             .map_err(|err| Error::Store(format!("failed to commit transaction: {err}")))?;
 
+        // Events are best effort, the cursors are moved whether they are delivered or not. They
+        // are notified once for the shard, listeners forward them to each of its workers.
+        if self.cache.is_empty() {
+            return Ok(());
+        }
+        let payloads: Vec<String> = self
+            .events
+            .iter()
+            .map(|event| Notification {
+                shard: self.shard.clone(),
+                event: event.clone(),
+            })
+            .filter_map(|notification| match serde_json::to_string(&notification) {
+                Ok(payload) => Some(payload),
+                Err(err) => {
+                    tracing::error!(err = err.to_string(), "failed to encode block event");
+                    None
+                }
+            })
+            .collect();
+        if !payloads.is_empty() {
+            if let Err(err) = conn
+                .execute(
+                    "SELECT pg_notify($1::TEXT, payload) FROM unnest($2::TEXT[]) AS payload",
+                    &[&CHANNEL, &payloads],
+                )
+                .await
+            {
+                tracing::error!(err = err.to_string(), "failed to notify block events");
+            }
+        }
+
         Ok(())
     }
 }
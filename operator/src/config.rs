@@ -1,6 +1,8 @@
 use lazy_static::lazy_static;
 use std::{env, time::Duration};
 
+const DEFAULT_FINALIZER_TIMEOUT_SECONDS: u64 = 24 * 60 * 60;
+
 lazy_static! {
     static ref CONTROLLER_CONFIG: Config = Config::from_env();
 }
@@ -14,6 +16,8 @@ pub struct Config {
     pub extension_domain: String,
     pub metrics_delay: Duration,
     pub prometheus_url: String,
+    /// Time the instances have to archive a deleted worker before its finalizer is removed.
+    pub finalizer_timeout: Duration,
 }
 
 impl Config {
@@ -28,6 +32,14 @@ impl Config {
                     .expect("METRICS_DELAY must be a number"),
             ),
             prometheus_url: env::var("PROMETHEUS_URL").expect("PROMETHEUS_URL must be set"),
+            finalizer_timeout: Duration::from_secs(
+                env::var("FINALIZER_TIMEOUT")
+                    .map(|x| {
+                        x.parse::<u64>()
+                            .expect("FINALIZER_TIMEOUT must be a number")
+                    })
+                    .unwrap_or(DEFAULT_FINALIZER_TIMEOUT_SECONDS),
+            ),
         }
     }
 }
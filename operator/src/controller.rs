@@ -1,16 +1,22 @@
+use chrono::Utc;
 use futures::StreamExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::{
+    api::{Patch, PatchParams},
     runtime::{controller::Action, watcher::Config as WatcherConfig, Controller},
     Api, Client, CustomResource, CustomResourceExt, ResourceExt,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
-use tracing::{error, info, instrument};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tracing::{error, info, instrument, warn};
 
-use crate::{build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{build_hostname, get_config, patch_resource_status, Error, Metrics, Result, State};
 
 pub static BALIUS_PORT_FINALIZER: &str = "baliusports.demeter.run";
+/// Finalizer the instances put on the workers they run, removed once they archived the worker
+/// so that its keys and data are purged according to its deletion policy.
+pub static ARCHIVE_FINALIZER: &str = "baliusworkers.demeter.run/archive";
 
 struct Context {
     pub client: Client,
@@ -52,6 +58,66 @@ pub struct BaliusWorkerSpec {
     pub url: String,
     pub config: serde_json::Map<String, serde_json::Value>,
     pub display_name: String,
+    /// Version to sign with for each of the worker's keys. Keys not listed sign with their latest
+    /// version.
+    pub key_versions: Option<BTreeMap<String, u64>>,
+    /// What happens to the worker's signing keys and data once it is deleted. Defaults to
+    /// `retain`.
+    pub deletion_policy: Option<DeletionPolicy>,
+    /// Limits on what and how often the worker can sign. Unrestricted when not set.
+    pub signing_policy: Option<SigningPolicy>,
+    /// URL of the worker's OpenRPC document, answered to `rpc.discover`. `s3://` and `http(s)://`
+    /// URLs are supported. When not set, `rpc.discover` is handled by the worker.
+    pub openrpc_url: Option<String>,
+    /// Record the worker's requests and responses so they can be replayed. Disabled by default.
+    pub capture_requests: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SigningPolicy {
+    /// Maximum number of signatures per minute. Unlimited when not set.
+    pub max_signatures_per_minute: Option<u32>,
+    /// Payloads the worker is allowed to sign. Defaults to `any`.
+    pub allowed_payloads: Option<AllowedPayloads>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AllowedPayloads {
+    /// Any payload.
+    #[default]
+    Any,
+    /// Only Cardano transaction body hashes (32 bytes).
+    TxHash,
+}
+
+impl BaliusWorker {
+    /// Identity of the worker in instances, `{namespace}.{name}`. Namespaces can't contain dots,
+    /// so the first dot always separates the namespace from the name.
+    pub fn worker_id(&self) -> String {
+        worker_id(&self.namespace().unwrap_or_default(), &self.name_any())
+    }
+}
+
+/// Identity of the worker `name` in `namespace`, see [`BaliusWorker::worker_id`].
+pub fn worker_id(namespace: &str, name: &str) -> String {
+    format!("{namespace}.{name}")
+}
+
+/// Split a worker id into its namespace and name.
+pub fn parse_worker_id(worker_id: &str) -> Option<(&str, &str)> {
+    worker_id.split_once('.')
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionPolicy {
+    /// Keep keys and data archived until released through the instance admin API.
+    #[default]
+    Retain,
+    /// Delete keys and data once the retention period is over.
+    Delete,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
@@ -61,9 +127,26 @@ pub struct BaliusWorkerStatus {
     pub authenticated_endpoint_url: Option<String>,
     pub auth_token: String,
     pub error: Option<String>,
+    /// Signing keys created by the worker, kept up to date by the instance running it.
+    pub keys: Option<Vec<WorkerKey>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerKey {
+    pub name: String,
+    pub algorithm: String,
+    /// Latest version of the key.
+    pub version: u64,
+    /// Hex encoded public key of the latest version.
+    pub public_key: String,
 }
 
 async fn reconcile(crd: Arc<BaliusWorker>, ctx: Arc<Context>) -> Result<Action> {
+    if let Some(deleted_at) = &crd.metadata.deletion_timestamp {
+        return expire_archive_finalizer(&crd, deleted_at, &ctx).await;
+    }
+
     let (hostname, hostname_key) = build_hostname(&crd.spec.auth_token);
     let path = crd.name_any();
 
@@ -88,6 +171,54 @@ async fn reconcile(crd: Arc<BaliusWorker>, ctx: Arc<Context>) -> Result<Action>
     Ok(Action::await_change())
 }
 
+/// Remove the archive finalizer of a worker that no instance archived within the finalizer
+/// timeout, e.g. because no instance runs its network anymore, so that its deletion isn't
+/// blocked forever. Its keys and data are left in place, unarchived: a new worker with the same
+/// id picks them up.
+async fn expire_archive_finalizer(
+    crd: &BaliusWorker,
+    deleted_at: &Time,
+    ctx: &Context,
+) -> Result<Action> {
+    if !crd.finalizers().iter().any(|x| x == ARCHIVE_FINALIZER) {
+        return Ok(Action::await_change());
+    }
+
+    let timeout = get_config().finalizer_timeout;
+    let elapsed = (Utc::now() - deleted_at.0).to_std().unwrap_or_default();
+    if elapsed < timeout {
+        return Ok(Action::requeue(timeout - elapsed));
+    }
+
+    let finalizers: Vec<String> = crd
+        .finalizers()
+        .iter()
+        .filter(|x| *x != ARCHIVE_FINALIZER)
+        .cloned()
+        .collect();
+    let api =
+        Api::<BaliusWorker>::namespaced(ctx.client.clone(), &crd.namespace().unwrap_or_default());
+    api.patch(
+        &crd.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(serde_json::json!({
+            "metadata": {
+                "finalizers": finalizers,
+                // Fails instead of overwriting an instance archiving the worker in the meantime.
+                "resourceVersion": crd.resource_version(),
+            }
+        })),
+    )
+    .await?;
+
+    warn!(
+        resource = crd.name_any(),
+        "worker wasn't archived by any instance in time, removed its archive finalizer"
+    );
+
+    Ok(Action::await_change())
+}
+
 fn error_policy(crd: Arc<BaliusWorker>, err: &Error, ctx: Arc<Context>) -> Action {
     error!(error = err.to_string(), "reconcile failed");
     ctx.metrics.reconcile_failure(&crd, err);
@@ -11,6 +11,8 @@ pub struct Config {
     pub ssl_key_path: String,
     pub balius_port: u16,
     pub balius_dns: String,
+    /// Secret sent to instances to authenticate the proxy.
+    pub balius_proxy_secret: Option<String>,
     pub health_endpoint: String,
 }
 impl Config {
@@ -37,6 +39,7 @@ impl Config {
                 .parse()
                 .expect("BALIUS_PORT must a number"),
             balius_dns: env::var("BALIUS_DNS").expect("BALIUS_DNS must be set"),
+            balius_proxy_secret: env::var("BALIUS_PROXY_SECRET").ok(),
             health_endpoint: "/dmtr_health".to_string(),
         }
     }
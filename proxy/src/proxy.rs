@@ -3,7 +3,7 @@ use lazy_static::lazy_static;
 use pingora::http::Method;
 use pingora::Result;
 use pingora::{
-    http::ResponseHeader,
+    http::{RequestHeader, ResponseHeader},
     proxy::{ProxyHttp, Session},
     upstreams::peer::HttpPeer,
 };
@@ -17,6 +17,13 @@ use crate::config::Config;
 use crate::{Consumer, State, Tier};
 
 static DMTR_API_KEY: &str = "dmtr-api-key";
+static TRACEPARENT: &str = "traceparent";
+static PROXY_SECRET_HEADER: &str = "x-balius-proxy-secret";
+static CONSUMER_HEADER: &str = "x-balius-consumer";
+/// OpenRPC discovery calls aren't counted against rate limits.
+static DISCOVER_METHOD: &str = "rpc.discover";
+/// Largest body buffered to tell whether the request is a discovery call.
+const MAX_DISCOVERY_BODY: usize = 1024;
 
 lazy_static! {
     static ref LEGACY_NETWORKS: HashMap<&'static str, String> = {
@@ -26,6 +33,46 @@ lazy_static! {
         m.insert("preview", "cardano-preview".into());
         m
     };
+    static ref TRACEPARENT_REGEX: Regex =
+        Regex::new(r"^00-([0-9a-f]{32})-([0-9a-f]{16})-[0-9a-f]{2}$").unwrap();
+}
+
+/// Whether the body is a JSON-RPC discovery call, or a batch of them.
+fn is_discovery(body: &[u8]) -> bool {
+    let is_discover = |call: &serde_json::Value| call["method"] == DISCOVER_METHOD;
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Array(calls)) => !calls.is_empty() && calls.iter().all(is_discover),
+        Ok(call) => is_discover(&call),
+        Err(_) => false,
+    }
+}
+
+/// W3C trace context of the request. Valid incoming ones are forwarded as they are, otherwise a
+/// new trace is started so the request can still be followed into the instance.
+fn traceparent(session: &Session) -> String {
+    session
+        .req_header()
+        .headers
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            // All zero trace and parent ids are invalid.
+            TRACEPARENT_REGEX.captures(value).is_some_and(|captures| {
+                captures
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .all(|id| id.as_str().chars().any(|c| c != '0'))
+            })
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            format!(
+                "00-{:032x}-{:016x}-01",
+                rand::random::<u128>(),
+                rand::random::<u64>()
+            )
+        })
 }
 
 pub fn handle_legacy_networks(network: &str) -> String {
@@ -117,7 +164,7 @@ impl BaliusProxy {
             .insert_header("Access-Control-Allow-Origin", "*")
             .unwrap();
         response
-            .insert_header("Access-Control-Allow-Methods", "POST")
+            .insert_header("Access-Control-Allow-Methods", "GET,POST")
             .unwrap();
         response
             .insert_header("Access-Control-Allow-Headers", "Content-Type,dmtr-api-key")
@@ -145,6 +192,7 @@ pub struct Context {
     instance: String,
     consumer: Consumer,
     is_health_request: bool,
+    traceparent: String,
 }
 
 #[async_trait]
@@ -178,6 +226,7 @@ impl ProxyHttp for BaliusProxy {
         }
 
         ctx.consumer = consumer.unwrap();
+        ctx.traceparent = traceparent(session);
         ctx.instance = format!(
             "balius-{}.{}:{}",
             handle_legacy_networks(&ctx.consumer.network),
@@ -185,7 +234,29 @@ impl ProxyHttp for BaliusProxy {
             self.config.balius_port
         );
 
-        if self.limiter(&ctx.consumer).await? {
+        // Discovery calls can only be told apart by their body. They are small, so only small
+        // bodies of a known length are worth reading before proxying. What is read is kept in
+        // the retry buffer, which is sent upstream ahead of the rest of the body.
+        let content_length = session
+            .get_header("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let mut discovery = false;
+        if session.req_header().method == Method::POST
+            && content_length.is_some_and(|length| length > 0 && length <= MAX_DISCOVERY_BODY)
+        {
+            session.enable_retry_buffering();
+            let mut body = Vec::new();
+            while let Some(chunk) = session.read_request_body().await? {
+                body.extend_from_slice(&chunk);
+                if body.len() > MAX_DISCOVERY_BODY {
+                    break;
+                }
+            }
+            discovery = body.len() <= MAX_DISCOVERY_BODY && is_discovery(&body);
+        }
+
+        if !discovery && self.limiter(&ctx.consumer).await? {
             session.respond_error(429).await?;
             return Ok(true);
         }
@@ -202,6 +273,51 @@ impl ProxyHttp for BaliusProxy {
         Ok(Box::new(http_peer))
     }
 
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // Instances address workers as `/{namespace}/{name}`, taken from the consumer the key
+        // belongs to. The first segment of the requested path is the worker name from the
+        // endpoint URL, anything after it is kept.
+        let uri = &upstream_request.uri;
+        let rest = uri
+            .path()
+            .trim_start_matches('/')
+            .split_once('/')
+            .map(|(_, rest)| format!("/{rest}"))
+            .unwrap_or_default();
+        let query = uri.query().map(|q| format!("?{q}")).unwrap_or_default();
+
+        let uri = format!(
+            "/{}/{}{rest}{query}",
+            ctx.consumer.namespace, ctx.consumer.port_name
+        )
+        .parse()
+        .map_err(|err| {
+            pingora::Error::because(
+                pingora::ErrorType::InternalError,
+                "building upstream uri",
+                err,
+            )
+        })?;
+        upstream_request.set_uri(uri);
+        upstream_request.insert_header(TRACEPARENT, &ctx.traceparent)?;
+
+        // Instances trust these headers, so whatever the client sent is replaced.
+        upstream_request.insert_header(CONSUMER_HEADER, ctx.consumer.to_string())?;
+        match &self.config.balius_proxy_secret {
+            Some(secret) => upstream_request.insert_header(PROXY_SECRET_HEADER, secret)?,
+            None => {
+                upstream_request.remove_header(PROXY_SECRET_HEADER);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn logging(
         &self,
         session: &mut Session,
@@ -213,7 +329,10 @@ impl ProxyHttp for BaliusProxy {
                 .response_written()
                 .map_or(0, |resp| resp.status.as_u16());
 
+            // The trace id is the second field of the traceparent.
+            let trace_id = ctx.traceparent.split('-').nth(1).unwrap_or_default();
             info!(
+                trace_id,
                 "{} response code: {response_code}",
                 self.request_summary(session, ctx)
             );
@@ -227,3 +346,27 @@ impl ProxyHttp for BaliusProxy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_bodies() {
+        assert!(is_discovery(
+            br#"{"jsonrpc":"2.0","method":"rpc.discover","id":1}"#
+        ));
+        assert!(is_discovery(
+            br#"[{"method":"rpc.discover","id":1},{"method":"rpc.discover","id":2}]"#
+        ));
+
+        // A single call that isn't discovery makes the whole batch count.
+        assert!(!is_discovery(
+            br#"[{"method":"rpc.discover","id":1},{"method":"get-balance","id":2}]"#
+        ));
+        assert!(!is_discovery(br#"{"method":"get-balance","id":1}"#));
+        assert!(!is_discovery(b"[]"));
+        assert!(!is_discovery(br#"{"method":"rpc.disc"#));
+        assert!(!is_discovery(b""));
+    }
+}